@@ -91,7 +91,11 @@ const server = rpc.server({
         }
         let [threadId, amount, timestamp] = args;
         messengerApi.getThreadHistory(threadId, amount, timestamp, (err, obj) => {
-            if (timestamp != undefined) history.pop();
+            if (err) {
+                callback(err);
+                return;
+            }
+            if (timestamp != undefined) obj.pop();
             callback(null, obj);
         });
     },
@@ -1,16 +1,21 @@
 use std::cell::RefCell;
-use std::cmp::min;
 use std::cmp::Ordering;
+use std::cmp::{max, min};
 use std::collections::BinaryHeap;
 use std::collections::HashMap;
 use std::vec::Drain;
 
-use common::constants::ZSTD_COMPRESSION_LEVEL;
-use failure::Error;
-use messenger::session::SESSION;
+use chunk::{Chunk, ChunkID, ChunkIndex};
+use common::constants::{CHUNK_SIZE, ZSTD_COMPRESSION_LEVEL};
+use failure::{err_msg, Error};
+use storage::StorageBackend;
 
 type BlockID = u64;
 
+fn missing(id: BlockID) -> Error {
+    err_msg(format!("Block {} is missing", id))
+}
+
 #[derive(Eq, PartialEq, Serialize, Deserialize)]
 pub struct Block {
     id: BlockID,
@@ -18,6 +23,9 @@ pub struct Block {
     data: Option<Vec<u8>>,
     url: Option<String>,
     used: u64,
+    /// Bytes still referenced by some file.
+    #[serde(default)]
+    live: u64,
     capacity: u64,
     dirty: bool,
 }
@@ -39,6 +47,7 @@ impl Block {
         Self {
             id,
             used: 0,
+            live: 0,
             capacity: size,
             url: None,
             data: None,
@@ -50,43 +59,127 @@ impl Block {
         self.capacity - self.used
     }
 
-    pub fn data(&mut self) -> &mut Vec<u8> {
-        match self.url.as_ref() {
-            Some(url) => self.data.get_or_insert_with(|| {
-                let mut data = Vec::new();
-                SESSION
-                    .lock()
-                    .expect("Could not acquire Session lock")
-                    .get_attachment(url, &mut data)
-                    .expect("Could not page data block");
-                zstd::decode_all(&data[..]).expect("Could not decode block")
-            }),
-            None => self.data.get_or_insert_with(Vec::new),
+    /// The bytes of `loc`, which must lie in this block.
+    fn slice<S: StorageBackend>(
+        &mut self,
+        backend: &mut S,
+        loc: &DataLoc,
+    ) -> Result<Vec<u8>, Error> {
+        let id = self.id;
+        let start = loc.offset as usize;
+        self.data(backend)?
+            .get(start..start + loc.size as usize)
+            .map(<[u8]>::to_vec)
+            .ok_or_else(|| missing(id))
+    }
+
+    /// The persisted part of the block, without its cached data.
+    fn record(&self) -> Self {
+        Self {
+            id: self.id,
+            used: self.used,
+            live: self.live,
+            capacity: self.capacity,
+            url: self.url.clone(),
+            data: None,
+            dirty: false,
+        }
+    }
+
+    pub fn data<S: StorageBackend>(&mut self, backend: &mut S) -> Result<&mut Vec<u8>, Error> {
+        if self.data.is_none() {
+            let data = match self.url.as_ref() {
+                Some(url) => zstd::decode_all(&backend.get_blob(url)?[..])?,
+                None => Vec::new(),
+            };
+            self.data = Some(data);
+        }
+        Ok(self.data.as_mut().unwrap())
+    }
+
+    fn write_at<S: StorageBackend>(
+        &mut self,
+        backend: &mut S,
+        offset: u64,
+        data: &[u8],
+    ) -> Result<(), Error> {
+        let start = offset as usize;
+        let id = self.id;
+        self.data(backend)?
+            .get_mut(start..start + data.len())
+            .ok_or_else(|| missing(id))?
+            .copy_from_slice(data);
+        self.dirty = true;
+        Ok(())
+    }
+
+    /// Hands a range back; only a range at the end frees space.
+    fn release(&mut self, loc: &DataLoc) {
+        self.live = self.live.saturating_sub(loc.size);
+        if self.used == loc.offset + loc.size {
+            self.used = loc.offset;
+            if let Some(ref mut data) = self.data {
+                data.truncate(loc.offset as usize);
+            }
         }
     }
 
-    fn fill(&mut self, data: &mut Drain<u8>) -> DataLoc {
+    fn fill<S: StorageBackend>(
+        &mut self,
+        backend: &mut S,
+        data: &mut Drain<u8>,
+    ) -> Result<DataLoc, Error> {
         let offset = self.used;
         let available_size = self.available();
         let data_size = data.len() as u64;
         let write_size = min(available_size, data_size);
-        self.data()
+        self.data(backend)?
             .splice(offset as usize.., data.take(available_size as usize));
-        self.used += data_size;
+        self.used += write_size;
+        self.live += write_size;
         self.dirty = true;
-        DataLoc {
+        Ok(DataLoc {
             block_id: self.id,
             offset,
             size: write_size,
-        }
+            chunk: None,
+            hole: false,
+        })
     }
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Clone, Serialize, Deserialize)]
 pub struct DataLoc {
     pub block_id: u64,
     pub offset: u64,
     pub size: u64,
+    /// A shared chunk, which `offset` is then relative to.
+    #[serde(default)]
+    pub chunk: Option<ChunkID>,
+    /// Set for a hole, which reads back as zeros and takes up no block.
+    #[serde(default)]
+    pub hole: bool,
+}
+
+impl DataLoc {
+    fn hole(size: u64) -> Self {
+        Self {
+            block_id: 0,
+            offset: 0,
+            size,
+            chunk: None,
+            hole: true,
+        }
+    }
+}
+
+/// Blocks uploaded and chunks stored since the last checkpoint.
+#[derive(Serialize, Deserialize)]
+pub struct PoolDelta {
+    blocks: Vec<Block>,
+    chunks: Vec<(ChunkID, Chunk)>,
+    block_id: BlockID,
+    chunk_id: ChunkID,
 }
 
 // Memory management
@@ -96,6 +189,8 @@ pub struct BlockPool {
     max_num_blocks: u64,
     block_size: u64,
     block_id: BlockID,
+    #[serde(default)]
+    chunks: ChunkIndex,
 }
 
 impl BlockPool {
@@ -105,6 +200,7 @@ impl BlockPool {
             max_num_blocks,
             block_size,
             block_id: 0,
+            chunks: ChunkIndex::default(),
         }
     }
 
@@ -123,32 +219,38 @@ impl BlockPool {
 
     pub fn find(&mut self, size: u64) -> Vec<BlockID> {
         let mut remaining = size;
-        let mut blocks = Vec::new();
+        let mut full_blocks = 0;
         let block_size = self.block_size;
         while remaining > block_size {
-            let block = self.create_block();
             remaining -= block_size;
-            blocks.push(block);
+            full_blocks += 1;
         }
-        if remaining > 0 {
-            let (block_id, available) = {
-                let arena = self.arena.borrow();
-                let mut heap = BinaryHeap::from(arena.values().collect::<Vec<_>>());
-                match heap.pop() {
-                    Some(block) => (block.id, block.available()),
-                    None => (0, 0),
-                }
-            };
-            if available >= remaining {
-                blocks.push(block_id);
-            } else {
-                blocks.push(self.create_block());
+        // Place the tail before creating the full blocks, otherwise the
+        // emptiest block would be one of those and the tail would not fit.
+        let tail = if remaining > 0 {
+            let arena = self.arena.borrow();
+            let mut heap = BinaryHeap::from(arena.values().collect::<Vec<_>>());
+            match heap.pop() {
+                Some(block) if block.available() >= remaining => Some(block.id),
+                _ => None,
             }
+        } else {
+            None
+        };
+        let mut blocks = (0..full_blocks)
+            .map(|_| self.create_block())
+            .collect::<Vec<_>>();
+        if remaining > 0 {
+            blocks.push(tail.unwrap_or_else(|| self.create_block()));
         }
         blocks
     }
 
-    pub fn alloc(&mut self, mut data: Vec<u8>) -> Vec<DataLoc> {
+    pub fn alloc<S: StorageBackend>(
+        &mut self,
+        backend: &mut S,
+        mut data: Vec<u8>,
+    ) -> Result<Vec<DataLoc>, Error> {
         let size = data.len() as u64;
         let blocks = self.find(size);
         let mut stream = data.drain(..);
@@ -156,34 +258,487 @@ impl BlockPool {
         blocks
             .iter()
             .map(|block_id| {
-                let block = arena.get_mut(block_id).unwrap();
-                block.fill(&mut stream)
+                let block = arena.get_mut(block_id).ok_or_else(|| missing(*block_id))?;
+                block.fill(backend, &mut stream)
             })
             .collect()
     }
 
-    pub fn sync(&mut self) -> Result<(), Error> {
+    /// Reads up to `size` bytes from `offset`, failing on a missing block.
+    pub fn read<S: StorageBackend>(
+        &mut self,
+        backend: &mut S,
+        locs: &[DataLoc],
+        offset: u64,
+        size: u64,
+    ) -> Result<Vec<u8>, Error> {
+        let end = offset + size;
+        let mut pos = 0;
+        let mut data = Vec::with_capacity(size as usize);
+        let arena = self.arena.get_mut();
+        for loc in locs {
+            let loc_end = pos + loc.size;
+            if loc_end > offset && pos < end && loc.hole {
+                data.resize(
+                    data.len() + (min(end, loc_end) - max(offset, pos)) as usize,
+                    0,
+                );
+            } else if loc_end > offset && pos < end {
+                let (block_id, block_offset) = self
+                    .chunks
+                    .locate(loc)
+                    .ok_or_else(|| err_msg("Found dangling chunk reference"))?;
+                let start = block_offset + max(offset, pos) - pos;
+                let stop = block_offset + min(end, loc_end) - pos;
+                let block = arena.get_mut(&block_id).ok_or_else(|| missing(block_id))?;
+                let bytes = block
+                    .data(backend)?
+                    .get(start as usize..stop as usize)
+                    .ok_or_else(|| missing(block_id))?;
+                data.extend_from_slice(bytes);
+            }
+            pos = loc_end;
+        }
+        Ok(data)
+    }
+
+    /// Writes `data` at `offset`, returning the first extent that changed.
+    pub fn write<S: StorageBackend>(
+        &mut self,
+        backend: &mut S,
+        locs: &mut Vec<DataLoc>,
+        offset: u64,
+        data: &[u8],
+    ) -> Result<Option<usize>, Error> {
+        let end = offset + data.len() as u64;
+        let mut changed = self.unshare(backend, locs, offset, end)?;
+        let mut pos = 0;
+        let mut idx = 0;
+        while idx < locs.len() {
+            let loc_end = pos + locs[idx].size;
+            if loc_end > offset && pos < end {
+                let start = max(offset, pos);
+                let stop = min(end, loc_end);
+                let piece = &data[(start - offset) as usize..(stop - offset) as usize];
+                if locs[idx].hole {
+                    changed = Some(changed.map_or(idx, |changed| min(changed, idx)));
+                    idx += self.fill_hole(backend, locs, idx, start - pos, piece)?;
+                    pos = loc_end;
+                    continue;
+                }
+                let loc = &locs[idx];
+                let block = self
+                    .arena
+                    .get_mut()
+                    .get_mut(&loc.block_id)
+                    .ok_or_else(|| missing(loc.block_id))?;
+                block.write_at(backend, loc.offset + start - pos, piece)?;
+            }
+            pos = loc_end;
+            idx += 1;
+        }
+        if end > pos {
+            // The last extent may grow
+            let last = locs.len().saturating_sub(1);
+            changed = Some(changed.map_or(last, |changed| min(changed, last)));
+            if offset > pos {
+                locs.push(DataLoc::hole(offset - pos));
+            }
+            self.append(backend, locs, &data[(max(offset, pos) - offset) as usize..])?;
+        }
+        Ok(changed)
+    }
+
+    /// Fills part of a hole, returning how many extents replace it.
+    fn fill_hole<S: StorageBackend>(
+        &mut self,
+        backend: &mut S,
+        locs: &mut Vec<DataLoc>,
+        idx: usize,
+        at: u64,
+        data: &[u8],
+    ) -> Result<usize, Error> {
+        let mut pieces = Vec::new();
+        if at > 0 {
+            pieces.push(DataLoc::hole(at));
+        }
+        self.append_plain(backend, &mut pieces, data.to_vec())?;
+        let rest = locs[idx].size - at - data.len() as u64;
+        if rest > 0 {
+            pieces.push(DataLoc::hole(rest));
+        }
+        let count = pieces.len();
+        locs.splice(idx..idx + 1, pieces);
+        Ok(count)
+    }
+
+    /// Cuts a file down to `size`, returning the first extent that changed.
+    pub fn truncate(&mut self, locs: &mut Vec<DataLoc>, size: u64) -> Option<usize> {
+        let mut pos = 0;
+        let mut trimmed = Vec::new();
+        let mut changed = None;
+        for (idx, loc) in locs.iter_mut().enumerate() {
+            let loc_size = loc.size;
+            if pos + loc_size > size {
+                changed = changed.or(Some(idx));
+                let keep = size.saturating_sub(pos);
+                if loc.chunk.is_none() {
+                    trimmed.push(DataLoc {
+                        offset: loc.offset + keep,
+                        size: loc_size - keep,
+                        ..loc.clone()
+                    });
+                } else if keep == 0 {
+                    // A chunk is only released along with the whole extent
+                    trimmed.push(loc.clone());
+                }
+                loc.size = keep;
+            }
+            pos += loc_size;
+        }
+        locs.retain(|loc| loc.size > 0);
+        // Later ranges first, so a block can shrink back across several of them
+        for loc in trimmed.iter().rev() {
+            self.release(loc);
+        }
+        changed
+    }
+
+    /// Bytes taken up, including freed ranges not reclaimed yet.
+    pub fn used(&self) -> u64 {
+        self.arena.borrow().values().map(|block| block.used).sum()
+    }
+
+    /// Blocks less than `threshold` full, if rewriting them saves space.
+    pub fn compactable(&self, threshold: f64) -> Vec<BlockID> {
+        let arena = self.arena.borrow();
+        let sparse = arena
+            .values()
+            .filter(|block| (block.live as f64) < threshold * block.capacity as f64)
+            .collect::<Vec<_>>();
+        if sparse.len() > 1 || sparse.iter().any(|block| block.live < block.used) {
+            sparse.iter().map(|block| block.id).collect()
+        } else {
+            Vec::new()
+        }
+    }
+
+    /// Takes blocks out of the pool, for `relocate` to copy out of.
+    pub fn retire(&mut self, ids: &[BlockID]) -> HashMap<BlockID, Block> {
+        let arena = self.arena.get_mut();
+        ids.iter()
+            .filter_map(|id| arena.remove(id).map(|block| (*id, block)))
+            .collect()
+    }
+
+    /// Puts retired blocks back, undoing `retire`.
+    pub fn unretire(&mut self, blocks: HashMap<BlockID, Block>) {
+        self.arena.get_mut().extend(blocks);
+    }
+
+    /// Copies the extents in `retired` blocks back into the pool.
+    pub fn relocate<S: StorageBackend>(
+        &mut self,
+        backend: &mut S,
+        retired: &mut HashMap<BlockID, Block>,
+        locs: &[DataLoc],
+    ) -> Result<Vec<DataLoc>, Error> {
+        let mut relocated = Vec::new();
+        for loc in locs {
+            match retired.get_mut(&loc.block_id) {
+                Some(block) => {
+                    let data = block.slice(backend, loc)?;
+                    self.append_plain(backend, &mut relocated, data)?;
+                }
+                None => relocated.push(loc.clone()),
+            }
+        }
+        Ok(relocated)
+    }
+
+    /// Copies chunks out of `retired` blocks, returning their old locations.
+    pub fn relocate_chunks<S: StorageBackend>(
+        &mut self,
+        backend: &mut S,
+        retired: &mut HashMap<BlockID, Block>,
+    ) -> Result<Vec<(ChunkID, DataLoc)>, Error> {
+        let chunks = self
+            .chunks
+            .iter()
+            .filter(|(_, chunk)| retired.contains_key(&chunk.loc.block_id))
+            .map(|(id, chunk)| (id, chunk.loc.clone()))
+            .collect::<Vec<_>>();
+        let mut moved = Vec::new();
+        for (id, loc) in chunks {
+            let copied = match retired.get_mut(&loc.block_id) {
+                Some(block) => block
+                    .slice(backend, &loc)
+                    .and_then(|data| self.alloc(backend, data)),
+                None => Err(missing(loc.block_id)),
+            };
+            match copied {
+                Ok(mut copy) => {
+                    self.chunks.relocate(id, copy.remove(0));
+                    moved.push((id, loc));
+                }
+                Err(err) => {
+                    self.unrelocate_chunks(moved);
+                    return Err(err);
+                }
+            }
+        }
+        Ok(moved)
+    }
+
+    /// Points chunks back at the locations `relocate_chunks` moved them from.
+    pub fn unrelocate_chunks(&mut self, moved: Vec<(ChunkID, DataLoc)>) {
+        for (id, loc) in moved {
+            self.chunks.relocate(id, loc);
+        }
+    }
+
+    /// Returns a range, or a chunk's last reference, to its block.
+    pub fn release(&mut self, loc: &DataLoc) {
+        if loc.hole {
+            return;
+        }
+        let loc = match loc.chunk {
+            Some(id) => match self.chunks.release(id) {
+                Some(chunk) => chunk.loc,
+                None => return,
+            },
+            None => loc.clone(),
+        };
+        let loc = &loc;
+        let arena = self.arena.get_mut();
+        let empty = match arena.get_mut(&loc.block_id) {
+            Some(block) => {
+                block.release(loc);
+                block.live == 0
+            }
+            None => false,
+        };
+        if empty {
+            arena.remove(&loc.block_id);
+        }
+    }
+
+    /// Rebuilds live byte counts from `locs` and drops unreferenced blocks.
+    pub fn recount<'a, I: Iterator<Item = &'a DataLoc>>(&mut self, locs: I) {
+        let arena = self.arena.get_mut();
+        for block in arena.values_mut() {
+            block.live = 0;
+        }
+        self.chunks.reset_refs();
+        for loc in locs {
+            match loc.chunk {
+                Some(id) => self.chunks.acquire(id),
+                None => {
+                    if let Some(block) = arena.get_mut(&loc.block_id) {
+                        block.live += loc.size;
+                    }
+                }
+            }
+        }
+        self.chunks.prune();
+        for (_, chunk) in self.chunks.iter() {
+            if let Some(block) = arena.get_mut(&chunk.loc.block_id) {
+                block.live += chunk.loc.size;
+            }
+        }
+        arena.retain(|_, block| block.live > 0);
+    }
+
+    /// Copies the chunks overlapping `offset..end` before they are written.
+    fn unshare<S: StorageBackend>(
+        &mut self,
+        backend: &mut S,
+        locs: &mut Vec<DataLoc>,
+        offset: u64,
+        end: u64,
+    ) -> Result<Option<usize>, Error> {
+        let mut pos = 0;
+        let mut idx = 0;
+        let mut changed = None;
+        while idx < locs.len() {
+            let size = locs[idx].size;
+            if locs[idx].chunk.is_some() && pos + size > offset && pos < end {
+                changed = changed.or(Some(idx));
+                let data = self.read(backend, &locs[idx..idx + 1], 0, size)?;
+                let copies = self.alloc(backend, data)?;
+                let shared = locs.remove(idx);
+                let count = copies.len();
+                locs.splice(idx..idx, copies);
+                self.release(&shared);
+                idx += count;
+            } else {
+                idx += 1;
+            }
+            pos += size;
+        }
+        Ok(changed)
+    }
+
+    /// Stores aligned `CHUNK_SIZE` pieces as chunks, the rest as is.
+    fn append<S: StorageBackend>(
+        &mut self,
+        backend: &mut S,
+        locs: &mut Vec<DataLoc>,
+        data: &[u8],
+    ) -> Result<(), Error> {
+        let pos = locs.iter().map(|loc| loc.size).sum::<u64>();
+        let head = min(
+            (CHUNK_SIZE - pos % CHUNK_SIZE) % CHUNK_SIZE,
+            data.len() as u64,
+        );
+        let (head, rest) = data.split_at(head as usize);
+        self.append_plain(backend, locs, head.to_vec())?;
+        for piece in rest.chunks(CHUNK_SIZE as usize) {
+            if piece.len() as u64 == CHUNK_SIZE {
+                self.append_chunk(backend, locs, piece)?;
+            } else {
+                self.append_plain(backend, locs, piece.to_vec())?;
+            }
+        }
+        Ok(())
+    }
+
+    fn append_chunk<S: StorageBackend>(
+        &mut self,
+        backend: &mut S,
+        locs: &mut Vec<DataLoc>,
+        data: &[u8],
+    ) -> Result<(), Error> {
+        let hash = blake3::hash(data).to_hex().to_string();
+        let id = match self.chunks.find(&hash) {
+            Some(id) => {
+                self.chunks.acquire(id);
+                id
+            }
+            None => {
+                // No bigger than a block, so `alloc` keeps it in one piece
+                let loc = self.alloc(backend, data.to_vec())?.remove(0);
+                self.chunks.insert(hash, loc)
+            }
+        };
+        locs.push(DataLoc {
+            block_id: 0,
+            offset: 0,
+            size: data.len() as u64,
+            chunk: Some(id),
+            hole: false,
+        });
+        Ok(())
+    }
+
+    /// Grows the last extent in place while its block has room.
+    fn append_plain<S: StorageBackend>(
+        &mut self,
+        backend: &mut S,
+        locs: &mut Vec<DataLoc>,
+        mut data: Vec<u8>,
+    ) -> Result<(), Error> {
+        if data.is_empty() {
+            return Ok(());
+        }
+        if let Some(last) = locs
+            .last_mut()
+            .filter(|last| last.chunk.is_none() && !last.hole)
+        {
+            let block = self
+                .arena
+                .get_mut()
+                .get_mut(&last.block_id)
+                .ok_or_else(|| missing(last.block_id))?;
+            if block.used == last.offset + last.size && block.available() > 0 {
+                let fits = min(block.available(), data.len() as u64) as usize;
+                let extended = block.fill(backend, &mut data.drain(..fits))?;
+                last.size += extended.size;
+            }
+        }
+        if data.is_empty() {
+            return Ok(());
+        }
+        for loc in self.alloc(backend, data)? {
+            match locs.last_mut() {
+                Some(ref mut last)
+                    if last.chunk.is_none()
+                        && !last.hole
+                        && last.block_id == loc.block_id
+                        && last.offset + last.size == loc.offset =>
+                {
+                    last.size += loc.size;
+                    continue;
+                }
+                _ => {}
+            }
+            locs.push(loc);
+        }
+        Ok(())
+    }
+
+    /// Blocks that have been uploaded, with their urls.
+    pub fn urls(&self) -> Vec<(BlockID, String)> {
+        self.arena
+            .borrow()
+            .values()
+            .filter_map(|block| block.url.clone().map(|url| (block.id, url)))
+            .collect()
+    }
+
+    /// Uploads a block again, e.g. under a new key.
+    pub fn reupload<S: StorageBackend>(
+        &mut self,
+        backend: &mut S,
+        id: BlockID,
+    ) -> Result<(), Error> {
+        if let Some(block) = self.arena.get_mut().get_mut(&id) {
+            if let Some(url) = block.url.clone() {
+                let data = backend.get_blob(&url)?;
+                block.url = Some(backend.put_blob(&data)?);
+            }
+        }
+        Ok(())
+    }
+
+    /// Uploads every block that changed, returning their ids.
+    pub fn sync<S: StorageBackend>(&mut self, backend: &mut S) -> Result<Vec<BlockID>, Error> {
         let mut arena = self.arena.borrow_mut();
-        arena.iter_mut().for_each(|(_, block)| {
+        let mut synced = Vec::new();
+        for block in arena.values_mut() {
             if block.dirty {
-                let mut session = SESSION.lock().expect("Could not acquire session lock");
-                let thread_id = session.fbid.clone();
-                let encoded =
-                    zstd::encode_all(&block.data.as_ref().unwrap()[..], ZSTD_COMPRESSION_LEVEL)
-                        .expect("Encoding failed")
-                        .iter()
-                        .map(|byte| *byte as char)
-                        .collect();
-                let resp = session
-                    .attachment(encoded, thread_id)
-                    .expect("Could not send attachment");
-                let message = session
-                    .get_message(resp.message_id)
-                    .expect("Could not find message that was just sent");
-                block.url = Some(message.attachments[0].url.clone());
+                let encoded = zstd::encode_all(&block.data(backend)?[..], ZSTD_COMPRESSION_LEVEL)?;
+                block.url = Some(backend.put_blob(&encoded)?);
                 block.dirty = false;
+                synced.push(block.id);
             }
-        });
-        Ok(())
+        }
+        Ok(synced)
+    }
+
+    /// The blocks `synced` and the chunks stored after `chunk_id`.
+    pub fn delta(&self, synced: &[BlockID], chunk_id: ChunkID) -> PoolDelta {
+        let arena = self.arena.borrow();
+        PoolDelta {
+            blocks: synced
+                .iter()
+                .filter_map(|id| arena.get(id).map(Block::record))
+                .collect(),
+            chunks: self.chunks.since(chunk_id),
+            block_id: self.block_id,
+            chunk_id: self.chunks.last_id(),
+        }
+    }
+
+    pub fn replay(&mut self, delta: PoolDelta) {
+        let arena = self.arena.get_mut();
+        arena.extend(delta.blocks.into_iter().map(|block| (block.id, block)));
+        self.chunks.replay(delta.chunks, delta.chunk_id);
+        self.block_id = max(self.block_id, delta.block_id);
+    }
+
+    /// Id of the newest chunk stored.
+    pub fn last_chunk_id(&self) -> ChunkID {
+        self.chunks.last_id()
     }
 }
@@ -0,0 +1,119 @@
+use std::cmp::max;
+use std::collections::HashMap;
+use std::mem;
+
+use block::DataLoc;
+
+pub type ChunkID = u64;
+
+/// File data stored once by content.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Chunk {
+    pub hash: String,
+    pub loc: DataLoc,
+    refs: u64,
+}
+
+/// Content-addressed chunks with the number of extents referring to each.
+#[derive(Default, Serialize, Deserialize)]
+pub struct ChunkIndex {
+    chunks: HashMap<ChunkID, Chunk>,
+    #[serde(skip)]
+    by_hash: HashMap<String, ChunkID>,
+    chunk_id: ChunkID,
+}
+
+impl ChunkIndex {
+    pub fn find(&self, hash: &str) -> Option<ChunkID> {
+        self.by_hash.get(hash).cloned()
+    }
+
+    pub fn get(&self, id: ChunkID) -> Option<&Chunk> {
+        self.chunks.get(&id)
+    }
+
+    /// Records a chunk stored at `loc`, with its first reference.
+    pub fn insert(&mut self, hash: String, loc: DataLoc) -> ChunkID {
+        self.chunk_id += 1;
+        self.by_hash.insert(hash.clone(), self.chunk_id);
+        self.chunks
+            .insert(self.chunk_id, Chunk { hash, loc, refs: 1 });
+        self.chunk_id
+    }
+
+    pub fn acquire(&mut self, id: ChunkID) {
+        if let Some(chunk) = self.chunks.get_mut(&id) {
+            chunk.refs += 1;
+        }
+    }
+
+    /// Drops a reference, returning the chunk once nothing refers to it.
+    pub fn release(&mut self, id: ChunkID) -> Option<Chunk> {
+        {
+            let chunk = self.chunks.get_mut(&id)?;
+            chunk.refs = chunk.refs.saturating_sub(1);
+            if chunk.refs > 0 {
+                return None;
+            }
+        }
+        let chunk = self.chunks.remove(&id)?;
+        self.by_hash.remove(&chunk.hash);
+        Some(chunk)
+    }
+
+    /// The block and offset holding the bytes of `loc`.
+    pub fn locate(&self, loc: &DataLoc) -> Option<(u64, u64)> {
+        match loc.chunk {
+            Some(id) => {
+                let chunk = self.get(id)?;
+                Some((chunk.loc.block_id, chunk.loc.offset + loc.offset))
+            }
+            None => Some((loc.block_id, loc.offset)),
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (ChunkID, &Chunk)> {
+        self.chunks.iter().map(|(id, chunk)| (*id, chunk))
+    }
+
+    /// Points a chunk at a new copy of its bytes, returning the old location.
+    pub fn relocate(&mut self, id: ChunkID, loc: DataLoc) -> Option<DataLoc> {
+        let chunk = self.chunks.get_mut(&id)?;
+        Some(mem::replace(&mut chunk.loc, loc))
+    }
+
+    pub fn last_id(&self) -> ChunkID {
+        self.chunk_id
+    }
+
+    /// The chunks stored after `id` that are still around.
+    pub fn since(&self, id: ChunkID) -> Vec<(ChunkID, Chunk)> {
+        self.chunks
+            .iter()
+            .filter(|(chunk_id, _)| **chunk_id > id)
+            .map(|(chunk_id, chunk)| (*chunk_id, chunk.clone()))
+            .collect()
+    }
+
+    /// Adds chunks from `since`, to be counted by the next recount.
+    pub fn replay(&mut self, chunks: Vec<(ChunkID, Chunk)>, chunk_id: ChunkID) {
+        self.chunks.extend(chunks);
+        self.chunk_id = max(self.chunk_id, chunk_id);
+    }
+
+    pub fn reset_refs(&mut self) {
+        for chunk in self.chunks.values_mut() {
+            chunk.refs = 0;
+        }
+    }
+
+    /// Forgets the chunks nothing refers to and rebuilds the hash lookup.
+    pub fn prune(&mut self) {
+        self.chunks.retain(|_, chunk| chunk.refs > 0);
+        self.by_hash = self
+            .chunks
+            .iter()
+            .map(|(id, chunk)| (chunk.hash.clone(), *id))
+            .collect();
+    }
+}
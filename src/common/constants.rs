@@ -1,6 +1,16 @@
-pub const USER_DIR: u16 = 0o755;
 pub const KILOBYTES: u64 = 1_000;
 pub const MEGABYTES: u64 = 1_000 * KILOBYTES;
+pub const GIGABYTES: u64 = 1_000 * MEGABYTES;
 pub const MAX_MESSAGE_FETCH: u64 = 300;
 pub const MESSAGE_BATCH_SIZE: u64 = 50;
 pub const ZSTD_COMPRESSION_LEVEL: i32 = 10;
+pub const CHUNK_SIZE: u64 = MEGABYTES;
+pub const WRITE_BUFFER_SIZE: u64 = MEGABYTES;
+pub const REKEY_BATCH_SIZE: usize = 16;
+pub const STATFS_BLOCK_SIZE: u64 = 4096;
+pub const MAX_NAME_LENGTH: u32 = 255;
+pub const METADATA_PADDING_MIN: u64 = 64 * KILOBYTES;
+pub const MAX_INLINE_METADATA: u64 = 12 * KILOBYTES;
+pub const METADATA_PART_SIZE: u64 = 5 * MEGABYTES;
+pub const CHECKPOINT_INTERVAL: u64 = 64;
+pub const COMPACTION_INTERVAL: u64 = 64;
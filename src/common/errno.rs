@@ -0,0 +1,31 @@
+use std::error;
+use std::fmt;
+use std::io;
+
+use failure::Error;
+use libc::c_int;
+
+/// Reported for a missing extended attribute; Linux calls it `ENODATA`.
+#[cfg(target_os = "macos")]
+pub const ENOATTR: c_int = libc::ENOATTR;
+#[cfg(not(target_os = "macos"))]
+pub const ENOATTR: c_int = libc::ENODATA;
+
+/// An error that should reach the kernel as a specific errno.
+#[derive(Debug)]
+pub struct Errno(pub c_int);
+
+impl fmt::Display for Errno {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", io::Error::from_raw_os_error(self.0))
+    }
+}
+
+impl error::Error for Errno {}
+
+/// The errno to reply with, or `default` for errors that don't carry one.
+pub fn errno(err: &Error, default: c_int) -> c_int {
+    err.downcast_ref::<Errno>()
+        .map(|errno| errno.0)
+        .unwrap_or(default)
+}
@@ -1,2 +1,4 @@
 pub mod constants;
+pub mod errno;
+pub mod permission;
 pub mod tree;
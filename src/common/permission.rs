@@ -0,0 +1,43 @@
+use fuse::{FileAttr, FileType, Request};
+use libc::{c_int, X_OK};
+
+const S_ISVTX: u16 = 0o1000;
+
+/// Who a FUSE request is made on behalf of.
+#[derive(Clone, Copy)]
+pub struct Caller {
+    pub uid: u32,
+    pub gid: u32,
+}
+
+impl<'a, 'b> From<&'b Request<'a>> for Caller {
+    fn from(req: &'b Request<'a>) -> Self {
+        Self {
+            uid: req.uid(),
+            gid: req.gid(),
+        }
+    }
+}
+
+/// Whether `uid` and `gid` may access `attr` with `mask`, like the kernel.
+pub fn permits(attr: &FileAttr, uid: u32, gid: u32, mask: c_int) -> bool {
+    let mask = mask as u16 & 0o7;
+    if uid == 0 {
+        return mask & X_OK as u16 == 0
+            || attr.kind == FileType::Directory
+            || attr.perm & 0o111 != 0;
+    }
+    let granted = if uid == attr.uid {
+        attr.perm >> 6
+    } else if gid == attr.gid {
+        attr.perm >> 3
+    } else {
+        attr.perm
+    };
+    mask & !granted & 0o7 == 0
+}
+
+/// Whether `uid` may remove `attr` from a possibly sticky `dir`.
+pub fn may_delete(dir: &FileAttr, attr: &FileAttr, uid: u32) -> bool {
+    dir.perm & S_ISVTX == 0 || uid == 0 || uid == dir.uid || uid == attr.uid
+}
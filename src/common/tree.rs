@@ -1,10 +1,12 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+use failure::{err_msg, Error};
 
 type NodeIdx = u64;
 
 #[derive(Serialize, Deserialize)]
 pub struct Node<T> {
-    pub children: Vec<NodeIdx>,
+    pub children: BTreeMap<String, NodeIdx>,
     pub parent: Option<NodeIdx>,
     pub entry: T,
 }
@@ -12,35 +14,103 @@ pub struct Node<T> {
 impl<T> Node<T> {
     pub fn new(parent: Option<NodeIdx>, entry: T) -> Self {
         Self {
-            children: Vec::new(),
+            children: BTreeMap::new(),
             parent,
             entry,
         }
     }
 }
 
+/// A change to the shape of the tree, as recorded for replay.
+#[derive(Clone, Serialize, Deserialize)]
+pub enum Change<T> {
+    Create {
+        parent: Option<NodeIdx>,
+        name: String,
+        idx: NodeIdx,
+        entry: T,
+    },
+    Link {
+        parent: NodeIdx,
+        name: String,
+        idx: NodeIdx,
+    },
+    Unlink {
+        parent: NodeIdx,
+        name: String,
+    },
+    Rename {
+        parent: NodeIdx,
+        name: String,
+        newparent: NodeIdx,
+        newname: String,
+    },
+    Remove {
+        idx: NodeIdx,
+    },
+}
+
 #[derive(Serialize, Deserialize)]
+#[serde(bound(deserialize = "T: ::serde::Deserialize<'de>"))]
 pub struct Tree<T> {
     arena: HashMap<NodeIdx, Node<T>>,
+    /// Changes to the shape of the tree since they were last cleared.
+    #[serde(skip)]
+    changes: Vec<Change<T>>,
+    /// Nodes whose entries may have changed since then.
+    #[serde(skip)]
+    touched: BTreeSet<NodeIdx>,
 }
 
-impl<T> Tree<T> {
+impl<T: Clone> Tree<T> {
     pub fn new() -> Self {
         Self {
             arena: HashMap::new(),
+            changes: Vec::new(),
+            touched: BTreeSet::new(),
         }
     }
 
-    pub fn add(&mut self, parent: Option<NodeIdx>, idx: NodeIdx, entry: T) {
-        let node = Node::new(parent, entry);
-        self.arena.insert(idx, node);
+    pub fn add(
+        &mut self,
+        parent: Option<NodeIdx>,
+        name: &str,
+        idx: NodeIdx,
+        entry: T,
+    ) -> Result<(), Error> {
+        self.insert(parent, name, idx, entry.clone())?;
+        self.changes.push(Change::Create {
+            parent,
+            name: name.to_owned(),
+            idx,
+            entry,
+        });
+        Ok(())
+    }
+
+    /// Fails without touching the tree if the change doesn't fit it.
+    fn insert(
+        &mut self,
+        parent: Option<NodeIdx>,
+        name: &str,
+        idx: NodeIdx,
+        entry: T,
+    ) -> Result<(), Error> {
         if let Some(parent) = parent {
-            let mut parent_node = self.arena.get_mut(&parent).expect("Found orphaned node");
-            parent_node.children.push(idx);
+            self.dir_mut(parent)?.children.insert(name.to_owned(), idx);
         }
+        self.arena.insert(idx, Node::new(parent, entry));
+        Ok(())
+    }
+
+    fn dir_mut(&mut self, idx: NodeIdx) -> Result<&mut Node<T>, Error> {
+        self.arena
+            .get_mut(&idx)
+            .ok_or_else(|| err_msg(format!("Found orphaned node under {}", idx)))
     }
 
     pub fn get_mut(&mut self, idx: NodeIdx) -> Option<&mut Node<T>> {
+        self.touched.insert(idx);
         self.arena.get_mut(&idx)
     }
 
@@ -48,12 +118,176 @@ impl<T> Tree<T> {
         self.arena.get(&idx)
     }
 
-    pub fn delete(&mut self, parent: Option<NodeIdx>, idx: NodeIdx) {
-        self.arena.remove(&idx);
-        if let Some(parent) = parent {
-            if let Some(mut node) = self.arena.get_mut(&parent) {
-                node.children.retain(|&child| child != idx);
+    pub fn nodes(&self) -> impl Iterator<Item = &Node<T>> {
+        self.arena.values()
+    }
+
+    pub fn nodes_mut(&mut self) -> impl Iterator<Item = &mut Node<T>> {
+        self.touched.extend(self.arena.keys());
+        self.arena.values_mut()
+    }
+
+    /// Number of nodes, i.e. inodes in use.
+    pub fn count(&self) -> u64 {
+        self.arena.len() as u64
+    }
+
+    pub fn lookup(&self, parent: NodeIdx, name: &str) -> Option<NodeIdx> {
+        self.arena
+            .get(&parent)
+            .and_then(|node| node.children.get(name))
+            .cloned()
+    }
+
+    /// Adds another directory entry for an existing node.
+    pub fn link(&mut self, parent: NodeIdx, name: &str, idx: NodeIdx) -> Result<(), Error> {
+        self.dir_mut(parent)?.children.insert(name.to_owned(), idx);
+        self.changes.push(Change::Link {
+            parent,
+            name: name.to_owned(),
+            idx,
+        });
+        Ok(())
+    }
+
+    /// Removes a directory entry but not its node.
+    pub fn unlink(&mut self, parent: NodeIdx, name: &str) -> Option<NodeIdx> {
+        let idx = self.arena.get_mut(&parent)?.children.remove(name)?;
+        self.changes.push(Change::Unlink {
+            parent,
+            name: name.to_owned(),
+        });
+        Some(idx)
+    }
+
+    /// Renames every entry, or none if `rename` fails for one.
+    pub fn rename_all<F, E>(&mut self, mut rename: F) -> Result<(), E>
+    where
+        F: FnMut(&str) -> Result<String, E>,
+    {
+        let mut renamed = HashMap::new();
+        for (idx, node) in &self.arena {
+            let children = node
+                .children
+                .iter()
+                .map(|(name, child)| Ok((rename(name)?, *child)))
+                .collect::<Result<BTreeMap<_, _>, E>>()?;
+            renamed.insert(*idx, children);
+        }
+        for (idx, children) in renamed {
+            if let Some(node) = self.arena.get_mut(&idx) {
+                node.children = children;
             }
         }
+        Ok(())
+    }
+
+    pub fn remove(&mut self, idx: NodeIdx) -> Option<Node<T>> {
+        let node = self.arena.remove(&idx)?;
+        self.changes.push(Change::Remove { idx });
+        Some(node)
+    }
+
+    /// Moves an entry, returning the node it replaced, if any.
+    pub fn rename(
+        &mut self,
+        parent: NodeIdx,
+        name: &str,
+        newparent: NodeIdx,
+        newname: &str,
+    ) -> Result<Option<NodeIdx>, Error> {
+        let replaced = self.move_entry(parent, name, newparent, newname)?;
+        self.changes.push(Change::Rename {
+            parent,
+            name: name.to_owned(),
+            newparent,
+            newname: newname.to_owned(),
+        });
+        Ok(replaced)
+    }
+
+    fn move_entry(
+        &mut self,
+        parent: NodeIdx,
+        name: &str,
+        newparent: NodeIdx,
+        newname: &str,
+    ) -> Result<Option<NodeIdx>, Error> {
+        self.dir_mut(newparent)?;
+        let idx = self
+            .dir_mut(parent)?
+            .children
+            .remove(name)
+            .ok_or_else(|| err_msg(format!("Found no entry {} to move", name)))?;
+        let replaced = self
+            .dir_mut(newparent)?
+            .children
+            .insert(newname.to_owned(), idx);
+        if let Some(node) = self.arena.get_mut(&idx) {
+            node.parent = Some(newparent);
+        }
+        Ok(replaced)
+    }
+
+    /// The changes recorded so far and the touched entries, mapped by `entry`.
+    pub fn changes<U, F>(&self, entry: F) -> (Vec<Change<T>>, Vec<(NodeIdx, U)>)
+    where
+        F: Fn(&T) -> U,
+    {
+        let touched = self
+            .touched
+            .iter()
+            .filter_map(|idx| Some((*idx, entry(&self.arena.get(idx)?.entry))))
+            .collect();
+        (self.changes.clone(), touched)
+    }
+
+    pub fn has_changes(&self) -> bool {
+        !self.changes.is_empty() || !self.touched.is_empty()
+    }
+
+    /// Forgets what has been recorded, once it has been saved.
+    pub fn clear_changes(&mut self) {
+        self.changes.clear();
+        self.touched.clear();
+    }
+
+    /// Applies a recorded change without recording it again.
+    pub fn replay(&mut self, change: Change<T>) -> Result<(), Error> {
+        match change {
+            Change::Create {
+                parent,
+                name,
+                idx,
+                entry,
+            } => self.insert(parent, &name, idx, entry),
+            Change::Link { parent, name, idx } => {
+                self.dir_mut(parent)?.children.insert(name, idx);
+                Ok(())
+            }
+            Change::Unlink { parent, name } => match self.dir_mut(parent)?.children.remove(&name) {
+                Some(_) => Ok(()),
+                None => Err(err_msg(format!("Found no entry {} to unlink", name))),
+            },
+            Change::Rename {
+                parent,
+                name,
+                newparent,
+                newname,
+            } => self
+                .move_entry(parent, &name, newparent, &newname)
+                .map(|_| ()),
+            Change::Remove { idx } => match self.arena.remove(&idx) {
+                Some(_) => Ok(()),
+                None => Err(err_msg(format!("Found no node {} to remove", idx))),
+            },
+        }
+    }
+
+    /// Updates an entry without recording it again.
+    pub fn replay_entry<F: FnOnce(&mut T)>(&mut self, idx: NodeIdx, update: F) {
+        if let Some(node) = self.arena.get_mut(&idx) {
+            update(&mut node.entry);
+        }
     }
 }
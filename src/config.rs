@@ -0,0 +1,56 @@
+use std::default::Default;
+use std::env;
+
+use common::constants::GIGABYTES;
+
+pub struct FsConfig {
+    /// Applied to new modes on top of the caller's umask.
+    pub umask: u16,
+    /// Leave permission checks to the kernel.
+    pub default_permissions: bool,
+    /// Capacity in bytes reported to `statfs`.
+    pub quota: u64,
+    /// Blocks less full than this are rewritten on flush.
+    pub compaction_threshold: f64,
+    /// Blocks and metadata are encrypted with a key derived from this.
+    pub passphrase: Option<String>,
+    /// Encrypt names and pad the snapshot. Needs a passphrase.
+    pub encrypt_names: bool,
+}
+
+impl Default for FsConfig {
+    fn default() -> Self {
+        Self {
+            umask: 0,
+            default_permissions: false,
+            quota: 100 * GIGABYTES,
+            compaction_threshold: 0.5,
+            passphrase: None,
+            encrypt_names: false,
+        }
+    }
+}
+
+impl FsConfig {
+    pub fn from_env() -> Self {
+        let mut config = Self::default();
+        if let Ok(umask) = env::var("MESSENGERFS_UMASK") {
+            config.umask = u16::from_str_radix(&umask, 8)
+                .expect("MESSENGERFS_UMASK must be an octal mode such as 022");
+        }
+        config.default_permissions = env::var("MESSENGERFS_DEFAULT_PERMISSIONS").is_ok();
+        if let Ok(quota) = env::var("MESSENGERFS_QUOTA") {
+            config.quota = quota
+                .parse()
+                .expect("MESSENGERFS_QUOTA must be a size in bytes");
+        }
+        if let Ok(threshold) = env::var("MESSENGERFS_COMPACTION_THRESHOLD") {
+            config.compaction_threshold = threshold
+                .parse()
+                .expect("MESSENGERFS_COMPACTION_THRESHOLD must be a fraction such as 0.5");
+        }
+        config.passphrase = env::var("MESSENGERFS_PASSPHRASE").ok();
+        config.encrypt_names = env::var("MESSENGERFS_ENCRYPT_NAMES").is_ok();
+        config
+    }
+}
@@ -0,0 +1,431 @@
+use std::collections::{BTreeMap, HashMap};
+use std::error;
+use std::fmt;
+
+use argon2::Argon2;
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng};
+use chacha20poly1305::{Key, XChaCha20Poly1305, XNonce};
+use failure::{err_msg, Error};
+
+use storage::{Candidate, StorageBackend};
+use superblock;
+
+const KEY_SIZE: usize = 32;
+const SALT_SIZE: usize = 16;
+const NONCE_SIZE: usize = 24;
+/// Names are padded to a multiple of this many bytes before encryption.
+const NAME_PADDING: usize = 32;
+/// Context for deriving the name key from a volume key.
+const NAME_KEY_CONTEXT: &str = "messenger-fs 2019 directory entry names";
+/// Starts a sealed snapshot: salt, wrapped keys and data, length-prefixed.
+const METADATA_MAGIC: &[u8] = b"MFSE";
+/// Starts the url of a sealed blob, followed by the key epoch.
+const BLOB_PREFIX: &str = "sealed:";
+
+/// Data that doesn't decrypt; never a reason to start a fresh filesystem.
+#[derive(Debug)]
+pub struct KeyError(pub &'static str);
+
+impl fmt::Display for KeyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl error::Error for KeyError {}
+
+fn derive_key(passphrase: &str, salt: &[u8]) -> Result<Vec<u8>, Error> {
+    let mut key = vec![0; KEY_SIZE];
+    Argon2::default()
+        .hash_password_into(passphrase.as_bytes(), salt, &mut key)
+        .map_err(|err| err_msg(format!("Could not derive key: {}", err)))?;
+    Ok(key)
+}
+
+fn random_bytes(size: usize) -> Vec<u8> {
+    let mut bytes = vec![0; size];
+    OsRng.fill_bytes(&mut bytes);
+    bytes
+}
+
+/// The key epoch and inner url of a sealed blob.
+fn parse_url(url: &str) -> Option<(u32, &str)> {
+    if !url.starts_with(BLOB_PREFIX) {
+        return None;
+    }
+    let mut parts = url[BLOB_PREFIX.len()..].splitn(2, ':');
+    let epoch = parts.next()?.parse().ok()?;
+    Some((epoch, parts.next()?))
+}
+
+/// Concatenates `fields`, each preceded by its length as a little endian u32.
+fn join_fields(fields: &[&[u8]]) -> Vec<u8> {
+    let mut joined = Vec::new();
+    for field in fields {
+        joined.extend_from_slice(&(field.len() as u32).to_le_bytes());
+        joined.extend_from_slice(field);
+    }
+    joined
+}
+
+fn split_fields(mut data: &[u8]) -> Result<Vec<Vec<u8>>, Error> {
+    let mut fields = Vec::new();
+    while !data.is_empty() {
+        if data.len() < 4 {
+            return Err(KeyError("Encrypted snapshot is malformed").into());
+        }
+        let size = u32::from_le_bytes([data[0], data[1], data[2], data[3]]) as usize;
+        if data.len() < 4 + size {
+            return Err(KeyError("Encrypted snapshot is malformed").into());
+        }
+        fields.push(data[4..4 + size].to_vec());
+        data = &data[4 + size..];
+    }
+    Ok(fields)
+}
+
+/// XChaCha20-Poly1305 under a single key.
+pub struct Cipher {
+    aead: XChaCha20Poly1305,
+}
+
+impl Cipher {
+    pub fn new(key: &[u8]) -> Self {
+        Self {
+            aead: XChaCha20Poly1305::new(Key::from_slice(key)),
+        }
+    }
+
+    /// Encrypts `data` under a fresh random nonce, which is prepended.
+    pub fn seal(&self, data: &[u8]) -> Result<Vec<u8>, Error> {
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let ciphertext = self
+            .aead
+            .encrypt(&nonce, data)
+            .map_err(|_| err_msg("Could not encrypt"))?;
+        let mut sealed = nonce.to_vec();
+        sealed.extend(ciphertext);
+        Ok(sealed)
+    }
+
+    pub fn open(&self, sealed: &[u8]) -> Result<Vec<u8>, Error> {
+        if sealed.len() < NONCE_SIZE {
+            return Err(KeyError("Encrypted data is truncated").into());
+        }
+        let (nonce, ciphertext) = sealed.split_at(NONCE_SIZE);
+        self.aead
+            .decrypt(XNonce::from_slice(nonce), ciphertext)
+            .map_err(|_| KeyError("Wrong passphrase or tampered data").into())
+    }
+}
+
+/// Deterministic name encryption, so entries can be looked up.
+pub struct NameCipher {
+    cipher: Cipher,
+    nonce_key: [u8; KEY_SIZE],
+}
+
+impl NameCipher {
+    pub fn new(key: &[u8]) -> Self {
+        let key = blake3::derive_key(NAME_KEY_CONTEXT, key);
+        Self {
+            cipher: Cipher::new(&key),
+            nonce_key: blake3::hash(&key).into(),
+        }
+    }
+
+    pub fn encrypt(&self, name: &str) -> String {
+        let mut padded = name.as_bytes().to_vec();
+        let size = (padded.len() / NAME_PADDING + 1) * NAME_PADDING;
+        padded.resize(size, 0);
+        let hash = blake3::keyed_hash(&self.nonce_key, &padded);
+        let nonce = XNonce::from_slice(&hash.as_bytes()[..NONCE_SIZE]);
+        let ciphertext = self
+            .cipher
+            .aead
+            .encrypt(nonce, padded.as_slice())
+            .expect("Could not encrypt name");
+        let mut sealed = nonce.to_vec();
+        sealed.extend(ciphertext);
+        base64::encode_config(&sealed, base64::URL_SAFE_NO_PAD)
+    }
+
+    pub fn decrypt(&self, stored: &str) -> Result<String, Error> {
+        let sealed = base64::decode_config(stored, base64::URL_SAFE_NO_PAD)
+            .map_err(|_| KeyError("Encrypted name is malformed"))?;
+        let mut name = self.cipher.open(&sealed)?;
+        while name.last() == Some(&0) {
+            name.pop();
+        }
+        Ok(String::from_utf8(name)?)
+    }
+}
+
+/// Random volume keys by epoch; data is sealed under the current one.
+#[derive(Serialize, Deserialize)]
+struct Keyring {
+    epoch: u32,
+    keys: BTreeMap<u32, Vec<u8>>,
+}
+
+impl Keyring {
+    fn new(epoch: u32, key: Vec<u8>) -> Self {
+        let mut keys = BTreeMap::new();
+        keys.insert(epoch, key);
+        Self { epoch, keys }
+    }
+
+    fn cipher(&self, epoch: u32) -> Result<Cipher, Error> {
+        match self.keys.get(&epoch) {
+            Some(key) => Ok(Cipher::new(key)),
+            None => Err(KeyError("No key for the epoch a block was sealed in").into()),
+        }
+    }
+
+    fn current(&self) -> Cipher {
+        Cipher::new(&self.keys[&self.epoch])
+    }
+}
+
+/// The keys a sealed snapshot was opened with.
+struct Unsealed {
+    salt: Vec<u8>,
+    wrapping: Cipher,
+    keyring: Keyring,
+}
+
+/// Seals blobs and metadata under volume keys wrapped by the passphrase.
+pub struct CryptoBackend<S> {
+    inner: S,
+    passphrase: Option<String>,
+    salt: Vec<u8>,
+    wrapping: Option<Cipher>,
+    keyring: Option<Keyring>,
+    /// Keys derived from the passphrase by salt, as Argon2 is slow on purpose.
+    derived: HashMap<Vec<u8>, Vec<u8>>,
+    /// Keys of the snapshots opened so far, by cursor.
+    unsealed: HashMap<String, Unsealed>,
+    /// Snapshot fetched last, whose keys seal its parts.
+    fetched: Option<String>,
+    accept_plaintext: bool,
+}
+
+impl<S> CryptoBackend<S> {
+    pub fn new(inner: S, passphrase: Option<String>) -> Self {
+        Self {
+            inner,
+            passphrase,
+            salt: Vec::new(),
+            wrapping: None,
+            keyring: None,
+            derived: HashMap::new(),
+            unsealed: HashMap::new(),
+            fetched: None,
+            accept_plaintext: false,
+        }
+    }
+
+    /// Reads plaintext despite the passphrase, to encrypt an old filesystem.
+    pub fn accept_plaintext(&mut self) {
+        self.accept_plaintext = true;
+    }
+
+    fn derive_key(&mut self, salt: &[u8]) -> Result<Vec<u8>, Error> {
+        if let Some(key) = self.derived.get(salt) {
+            return Ok(key.clone());
+        }
+        let key = derive_key(
+            self.passphrase
+                .as_ref()
+                .ok_or(KeyError("No passphrase is set"))?,
+            salt,
+        )?;
+        self.derived.insert(salt.to_vec(), key.clone());
+        Ok(key)
+    }
+
+    /// The volume keys, fresh ones until a snapshot supplies them.
+    fn keyring(&mut self) -> Result<Option<&mut Keyring>, Error> {
+        if self.passphrase.is_none() {
+            return Ok(None);
+        }
+        if self.keyring.is_none() {
+            self.salt = random_bytes(SALT_SIZE);
+            self.wrapping = None;
+            self.keyring = Some(Keyring::new(1, random_bytes(KEY_SIZE)));
+        }
+        Ok(self.keyring.as_mut())
+    }
+
+    fn wrapping(&mut self) -> Result<&Cipher, Error> {
+        if self.wrapping.is_none() {
+            let salt = self.salt.clone();
+            self.wrapping = Some(Cipher::new(&self.derive_key(&salt)?));
+        }
+        Ok(self.wrapping.as_ref().unwrap())
+    }
+
+    /// Whether the blob at `url` is sealed under the current volume key.
+    pub fn is_current(&self, url: &str) -> bool {
+        match (&self.keyring, parse_url(url)) {
+            (Some(keyring), Some((epoch, _))) => epoch == keyring.epoch,
+            (Some(_), None) => false,
+            (None, _) => true,
+        }
+    }
+
+    /// Starts sealing under a new random volume key.
+    pub fn rotate(&mut self) -> Result<(), Error> {
+        let keyring = self
+            .keyring()?
+            .ok_or(KeyError("Rekeying needs a passphrase"))?;
+        keyring.epoch = keyring.keys.keys().max().map_or(1, |epoch| epoch + 1);
+        keyring.keys.insert(keyring.epoch, random_bytes(KEY_SIZE));
+        Ok(())
+    }
+
+    /// Forgets every volume key but the current one, once no block needs them.
+    pub fn retire_keys(&mut self) {
+        if let Some(ref mut keyring) = self.keyring {
+            let epoch = keyring.epoch;
+            keyring.keys.retain(|key_epoch, _| *key_epoch == epoch);
+        }
+    }
+
+    /// Key for directory entry names; it changes with the volume key.
+    pub fn name_key(&mut self) -> Result<Option<Vec<u8>>, Error> {
+        Ok(self
+            .keyring()?
+            .map(|keyring| keyring.keys[&keyring.epoch].clone()))
+    }
+
+    /// Wraps the volume keys under a new passphrase from the next snapshot on.
+    pub fn rewrap(&mut self, passphrase: String) -> Result<(), Error> {
+        self.keyring()?
+            .ok_or(KeyError("Changing the passphrase needs the current one"))?;
+        self.passphrase = Some(passphrase);
+        self.derived.clear();
+        self.salt = random_bytes(SALT_SIZE);
+        self.wrapping = None;
+        Ok(())
+    }
+}
+
+impl<S: StorageBackend> StorageBackend for CryptoBackend<S> {
+    fn put_blob(&mut self, data: &[u8]) -> Result<String, Error> {
+        let (epoch, sealed) = match self.keyring()? {
+            Some(keyring) => (keyring.epoch, keyring.current().seal(data)?),
+            None => return self.inner.put_blob(data),
+        };
+        let url = self.inner.put_blob(&sealed)?;
+        Ok(format!("{}{}:{}", BLOB_PREFIX, epoch, url))
+    }
+
+    fn get_blob(&mut self, url: &str) -> Result<Vec<u8>, Error> {
+        let (epoch, url) = match parse_url(url) {
+            Some(parsed) => parsed,
+            None if self.passphrase.is_some() && !self.accept_plaintext => {
+                return Err(KeyError(
+                    "Found an unencrypted block, run `messenger-fs encrypt` to encrypt it",
+                )
+                .into())
+            }
+            None => return self.inner.get_blob(url),
+        };
+        let fetched = self
+            .fetched
+            .as_ref()
+            .and_then(|cursor| self.unsealed.get(cursor))
+            .map(|unsealed| &unsealed.keyring);
+        let cipher = match fetched.or(self.keyring.as_ref()) {
+            Some(keyring) => keyring.cipher(epoch)?,
+            None => {
+                return Err(KeyError("Found an encrypted block but no passphrase is set").into())
+            }
+        };
+        cipher.open(&self.inner.get_blob(url)?)
+    }
+
+    fn post_metadata(&mut self, metadata: &[u8]) -> Result<(), Error> {
+        let (keys, sealed) = match self.keyring()? {
+            Some(keyring) => (
+                serde_json::to_vec(keyring)?,
+                keyring.current().seal(metadata)?,
+            ),
+            None => return self.inner.post_metadata(metadata),
+        };
+        let wrapped = self.wrapping()?.seal(&keys)?;
+        let mut envelope = METADATA_MAGIC.to_vec();
+        envelope.extend(join_fields(&[&self.salt, &wrapped, &sealed]));
+        self.inner.post_metadata(&envelope)
+    }
+
+    fn fetch_metadata(&mut self, before: Option<&str>) -> Result<Option<Candidate>, Error> {
+        let Candidate { cursor, metadata } = match self.inner.fetch_metadata(before)? {
+            Some(candidate) => candidate,
+            None => return Ok(None),
+        };
+        let sealed = match metadata {
+            Ok(ref metadata) => metadata.starts_with(METADATA_MAGIC),
+            Err(_) => false,
+        };
+        // Not a damaged snapshot: passing over it to one from before the
+        // filesystem was encrypted would lose everything since
+        if sealed && self.passphrase.is_none() {
+            return Err(KeyError("The filesystem is encrypted but no passphrase is set").into());
+        }
+        let metadata = metadata.and_then(|metadata| self.open_metadata(&cursor, metadata));
+        self.fetched = Some(cursor.clone());
+        Ok(Some(Candidate { cursor, metadata }))
+    }
+
+    fn accept_metadata(&mut self, cursor: &str) -> Result<(), Error> {
+        if let Some(unsealed) = self.unsealed.remove(cursor) {
+            self.salt = unsealed.salt;
+            self.wrapping = Some(unsealed.wrapping);
+            self.keyring = Some(unsealed.keyring);
+        }
+        self.unsealed.clear();
+        self.fetched = None;
+        self.inner.accept_metadata(cursor)
+    }
+
+    fn has_blobs(&mut self) -> Result<bool, Error> {
+        self.inner.has_blobs()
+    }
+}
+
+impl<S> CryptoBackend<S> {
+    /// Opens a snapshot, keeping its keys aside under `cursor`.
+    fn open_metadata(&mut self, cursor: &str, metadata: Vec<u8>) -> Result<Vec<u8>, Error> {
+        if !metadata.starts_with(METADATA_MAGIC) {
+            if self.passphrase.is_some() && superblock::is_snapshot(&metadata) {
+                if !self.accept_plaintext {
+                    return Err(KeyError(
+                        "Found an unencrypted snapshot, run `messenger-fs encrypt` to encrypt it",
+                    )
+                    .into());
+                }
+                println!("Snapshot is not encrypted yet, it will be from the next flush");
+            }
+            return Ok(metadata);
+        }
+        let parts = split_fields(&metadata[METADATA_MAGIC.len()..])?;
+        if parts.len() != 3 {
+            return Err(KeyError("Encrypted snapshot is malformed").into());
+        }
+        let wrapping = Cipher::new(&self.derive_key(&parts[0])?);
+        let keyring: Keyring = serde_json::from_slice(&wrapping.open(&parts[1])?)?;
+        let metadata = keyring.current().open(&parts[2])?;
+        self.unsealed.insert(
+            cursor.to_string(),
+            Unsealed {
+                salt: parts[0].clone(),
+                wrapping,
+                keyring,
+            },
+        );
+        Ok(metadata)
+    }
+}
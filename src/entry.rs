@@ -1,25 +1,41 @@
+use std::collections::BTreeMap;
+
 use fuse::{FileAttr, FileType};
 
 use block::DataLoc;
 
-#[derive(Serialize, Deserialize)]
+#[derive(Clone, Serialize, Deserialize)]
 pub struct FileSystemEntry {
-    pub name: String,
     pub attr: EncodeFileAttr,
     pub data: Option<Vec<DataLoc>>,
+    #[serde(default)]
+    pub link: Option<String>,
+    #[serde(default)]
+    pub xattrs: BTreeMap<String, Vec<u8>>,
 }
 
 impl FileSystemEntry {
-    pub fn new(name: String, attr: FileAttr) -> Self {
+    pub fn new(attr: FileAttr) -> Self {
         Self {
             data: None,
+            link: None,
+            xattrs: BTreeMap::new(),
             attr: EncodeFileAttr::marshal(attr),
-            name,
+        }
+    }
+
+    /// A copy of everything but the extents.
+    pub fn without_data(&self) -> Self {
+        Self {
+            attr: self.attr.clone(),
+            data: None,
+            link: self.link.clone(),
+            xattrs: self.xattrs.clone(),
         }
     }
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Clone, Serialize, Deserialize)]
 pub enum EncodeFileType {
     Directory,
     RegularFile,
@@ -43,6 +59,13 @@ impl EncodeFileType {
         }
     }
 
+    pub fn is_dir(&self) -> bool {
+        match self {
+            EncodeFileType::Directory => true,
+            _ => false,
+        }
+    }
+
     pub fn unmarshal(&self) -> FileType {
         match self {
             EncodeFileType::Directory => FileType::Directory,
@@ -56,7 +79,7 @@ impl EncodeFileType {
     }
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Clone, Serialize, Deserialize)]
 pub struct EncodeTimespec {
     pub sec: i64,
     pub nsec: i32,
@@ -78,7 +101,7 @@ impl EncodeTimespec {
     }
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Clone, Serialize, Deserialize)]
 pub struct EncodeFileAttr {
     pub ino: u64,
     pub size: u64,
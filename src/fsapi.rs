@@ -1,21 +1,25 @@
 use std::ffi::OsStr;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
+use failure::Error;
 use fuse::{
-    FileAttr, FileType, Filesystem, ReplyAttr, ReplyCreate, ReplyData, ReplyDirectory, ReplyEmpty,
-    ReplyEntry, ReplyOpen, ReplyWrite, Request,
+    FileType, Filesystem, ReplyAttr, ReplyCreate, ReplyData, ReplyDirectory, ReplyEmpty,
+    ReplyEntry, ReplyOpen, ReplyStatfs, ReplyWrite, ReplyXattr, Request,
 };
-use libc::{EIO, ENFILE, ENOENT};
+use libc::{c_int, EIO, ENFILE, ENOENT, ERANGE};
 use time::Timespec;
 
+use common::constants::{MAX_NAME_LENGTH, STATFS_BLOCK_SIZE};
+use common::errno::errno;
+use common::permission::Caller;
 use common::tree::Node;
-use entry::EncodeFileAttr;
-use messengerfs::MessengerFS;
+use messengerfs::{MessengerFS, SetAttr};
+use storage::StorageBackend;
 
-impl Filesystem for MessengerFS {
+impl<S: StorageBackend> Filesystem for MessengerFS<S> {
     fn getattr(&mut self, _req: &Request, ino: u64, reply: ReplyAttr) {
         println!("getattr(ino={})", ino);
-        match self.find(ino) {
+        match self.fs.get(ino) {
             Some(Node { entry, .. }) => {
                 let ttl = Timespec::new(1, 0);
                 reply.attr(&ttl, &entry.attr.unmarshal());
@@ -26,9 +30,9 @@ impl Filesystem for MessengerFS {
 
     fn setattr(
         &mut self,
-        _req: &Request,
+        req: &Request,
         ino: u64,
-        _mode: Option<u32>,
+        mode: Option<u32>,
         uid: Option<u32>,
         gid: Option<u32>,
         size: Option<u64>,
@@ -42,45 +46,69 @@ impl Filesystem for MessengerFS {
         reply: ReplyAttr,
     ) {
         println!("setattr()");
-        match self.find(ino) {
-            Some(Node { entry, .. }) => {
-                let attr = entry.attr.unmarshal();
-                let attr = FileAttr {
-                    ino,
-                    blocks: attr.blocks,
-                    perm: attr.perm,
-                    nlink: attr.nlink,
-                    rdev: attr.rdev,
-                    kind: attr.kind,
-                    uid: uid.unwrap_or(attr.uid),
-                    gid: gid.unwrap_or(attr.gid),
-                    size: size.unwrap_or(attr.size),
-                    atime: atime.unwrap_or(attr.atime),
-                    mtime: mtime.unwrap_or(attr.mtime),
-                    crtime: crtime.unwrap_or(attr.crtime),
-                    ctime: chgtime.unwrap_or(attr.ctime),
-                    flags: flags.unwrap_or(attr.flags),
-                };
-                entry.attr = EncodeFileAttr::marshal(attr);
+        let changes = SetAttr {
+            mode,
+            uid,
+            gid,
+            size,
+            atime,
+            mtime,
+            crtime,
+            chgtime,
+            flags,
+        };
+        match self.fs_setattr(&Caller::from(req), ino, &changes) {
+            Ok(attr) => {
                 let ttl = Timespec::new(1, 0);
                 reply.attr(&ttl, &attr);
             }
-            None => reply.error(ENOENT),
+            Err(err) => reply.error(errno(&err, ENOENT)),
         }
     }
 
     fn setxattr(
         &mut self,
-        _req: &Request,
-        _ino: u64,
-        _name: &OsStr,
-        _value: &[u8],
-        _flags: u32,
+        req: &Request,
+        ino: u64,
+        name: &OsStr,
+        value: &[u8],
+        flags: u32,
         _position: u32,
         reply: ReplyEmpty,
     ) {
-        println!("setxattr()");
-        reply.ok();
+        println!("setxattr(ino={}, name={:?})", ino, name);
+        match self.fs_setxattr(&Caller::from(req), ino, name, value, flags) {
+            Ok(()) => reply.ok(),
+            Err(err) => reply.error(errno(&err, EIO)),
+        };
+    }
+
+    fn getxattr(&mut self, req: &Request, ino: u64, name: &OsStr, size: u32, reply: ReplyXattr) {
+        println!("getxattr(ino={}, name={:?}, size={})", ino, name, size);
+        match self.fs_getxattr(&Caller::from(req), ino, name) {
+            Ok(ref value) if size == 0 => reply.size(value.len() as u32),
+            Ok(ref value) if value.len() > size as usize => reply.error(ERANGE),
+            Ok(value) => reply.data(&value),
+            Err(err) => reply.error(errno(&err, EIO)),
+        };
+    }
+
+    fn listxattr(&mut self, req: &Request, ino: u64, size: u32, reply: ReplyXattr) {
+        println!("listxattr(ino={}, size={})", ino, size);
+        match self.fs_listxattr(&Caller::from(req), ino) {
+            Ok(ref names) if size == 0 => reply.size(names.len() as u32),
+            Ok(ref names) if names.len() > size as usize => reply.error(ERANGE),
+            Ok(names) => reply.data(&names),
+            Err(err) => reply.error(errno(&err, EIO)),
+        };
+    }
+
+    fn removexattr(&mut self, req: &Request, ino: u64, name: &OsStr, reply: ReplyEmpty) {
+        println!("removexattr(ino={}, name={:?})", ino, name);
+        match self.fs_removexattr(&Caller::from(req), ino, name) {
+            Ok(()) => reply.ok(),
+            Err(err) => reply.error(errno(&err, EIO)),
+        };
     }
 
     fn readdir(
@@ -93,10 +121,21 @@ impl Filesystem for MessengerFS {
     ) {
         println!("readdir(ino={}, fh={}, offset={})", ino, fh, offset);
         let node = self
-            .find(ino)
-            .as_ref()
+            .fs
+            .get(ino)
             .map(|node| (node.parent.unwrap_or(ino), node.children.clone()));
         if let Some((parent, children)) = node {
+            let children = match children
+                .iter()
+                .map(|(name, &nodeid)| Ok((self.decode_name(name)?, nodeid)))
+                .collect::<Result<Vec<_>, Error>>()
+            {
+                Ok(children) => children,
+                Err(err) => {
+                    reply.error(errno(&err, EIO));
+                    return;
+                }
+            };
             if offset == 0 {
                 reply.add(ino, ino as i64, FileType::Directory, &PathBuf::from("."));
                 reply.add(
@@ -105,13 +144,13 @@ impl Filesystem for MessengerFS {
                     FileType::Directory,
                     &PathBuf::from(".."),
                 );
-                children.clone().iter().for_each(|&nodeid| {
-                    let child = self.find(nodeid).expect("Child entry not found");
+                children.into_iter().for_each(|(name, nodeid)| {
+                    let child = self.fs.get(nodeid).expect("Child entry not found");
                     reply.add(
                         nodeid,
                         nodeid as i64,
                         child.entry.attr.kind.unmarshal(),
-                        &PathBuf::from(child.entry.name.clone()),
+                        &PathBuf::from(name),
                     );
                 });
             }
@@ -121,21 +160,22 @@ impl Filesystem for MessengerFS {
         }
     }
 
-    fn lookup(&mut self, _req: &Request, _parent: u64, name: &OsStr, reply: ReplyEntry) {
-        println!("lookup()");
-        let inode = match self.inodes.get(name.to_str().unwrap()) {
-            Some(&inode) => inode,
-            None => {
-                reply.error(ENOENT);
-                return;
-            }
+    fn readlink(&mut self, _req: &Request, ino: u64, reply: ReplyData) {
+        println!("readlink(ino={})", ino);
+        match self.fs_readlink(ino) {
+            Ok(link) => reply.data(&link),
+            Err(err) => reply.error(errno(&err, EIO)),
         };
-        match self.find(inode) {
-            Some(Node { entry, .. }) => {
+    }
+
+    fn lookup(&mut self, req: &Request, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        println!("lookup()");
+        match self.fs_lookup(&Caller::from(req), parent, name) {
+            Ok(attr) => {
                 let ttl = Timespec::new(1, 0);
-                reply.entry(&ttl, &entry.attr.unmarshal(), 0);
+                reply.entry(&ttl, &attr, 0);
             }
-            None => reply.error(ENOENT),
+            Err(err) => reply.error(errno(&err, EIO)),
         };
     }
 
@@ -155,7 +195,7 @@ impl Filesystem for MessengerFS {
         let result = self.fs_read(ino, fh, offset, size);
         match result {
             Ok(data) => reply.data(&data),
-            Err(_) => reply.error(ENOENT),
+            Err(err) => reply.error(errno(&err, EIO)),
         };
     }
 
@@ -179,31 +219,57 @@ impl Filesystem for MessengerFS {
             Ok(written) => {
                 reply.written(written);
             }
-            Err(_) => reply.error(EIO),
+            Err(err) => reply.error(errno(&err, EIO)),
         }
     }
 
-    fn open(&mut self, _req: &Request, ino: u64, flags: u32, reply: ReplyOpen) {
+    fn open(&mut self, req: &Request, ino: u64, flags: u32, reply: ReplyOpen) {
         println!("open(ino={}, flags={})", ino, flags);
-        let result = self.fs_open(ino, flags);
+        let result = self.fs_open(&Caller::from(req), ino, flags);
         match result {
             Ok(fh) => reply.opened(fh, 0),
-            Err(_) => reply.error(ENOENT),
+            Err(err) => reply.error(errno(&err, EIO)),
         }
     }
 
-    fn opendir(&mut self, _req: &Request, ino: u64, flags: u32, reply: ReplyOpen) {
+    fn opendir(&mut self, req: &Request, ino: u64, flags: u32, reply: ReplyOpen) {
         println!("opendir(ino={}, flags={})", ino, flags);
-        let result = self.fs_open(ino, flags);
+        let result = self.fs_open(&Caller::from(req), ino, flags);
         match result {
             Ok(fh) => reply.opened(fh, 0),
-            Err(_) => reply.error(ENOENT),
+            Err(err) => reply.error(errno(&err, EIO)),
         }
     }
 
-    fn fsync(&mut self, _req: &Request, _ino: u64, _fh: u64, _datasync: bool, reply: ReplyEmpty) {
-        println!("fsync()");
-        reply.ok();
+    fn access(&mut self, req: &Request, ino: u64, mask: u32, reply: ReplyEmpty) {
+        println!("access(ino={}, mask={:#o})", ino, mask);
+        match self.fs_access(&Caller::from(req), ino, mask as c_int) {
+            Ok(()) => reply.ok(),
+            Err(err) => reply.error(errno(&err, EIO)),
+        }
+    }
+
+    fn statfs(&mut self, _req: &Request, _ino: u64, reply: ReplyStatfs) {
+        println!("statfs()");
+        let stats = self.fs_statfs();
+        reply.statfs(
+            stats.blocks,
+            stats.free,
+            stats.free,
+            stats.files,
+            stats.ffree,
+            STATFS_BLOCK_SIZE as u32,
+            MAX_NAME_LENGTH,
+            STATFS_BLOCK_SIZE as u32,
+        );
+    }
+
+    fn fsync(&mut self, _req: &Request, ino: u64, fh: u64, _datasync: bool, reply: ReplyEmpty) {
+        println!("fsync(ino={}, fh={})", ino, fh);
+        match self.fs_fsync(ino, fh) {
+            Ok(()) => reply.ok(),
+            Err(err) => reply.error(errno(&err, EIO)),
+        }
     }
 
     fn flush(&mut self, _req: &Request, _ino: u64, _fh: u64, _lock_owner: u64, reply: ReplyEmpty) {
@@ -211,7 +277,7 @@ impl Filesystem for MessengerFS {
         let result = self.fs_flush();
         match result {
             Ok(()) => reply.ok(),
-            Err(_) => reply.error(ENOENT),
+            Err(err) => reply.error(errno(&err, EIO)),
         }
     }
 
@@ -220,68 +286,147 @@ impl Filesystem for MessengerFS {
         req: &Request,
         parent: u64,
         name: &OsStr,
-        _mode: u32,
-        _flags: u32,
+        mode: u32,
+        flags: u32,
         reply: ReplyCreate,
     ) {
         println!(
-            "create(parent={}, name={}, mode={:#b}, flags={:#b})",
-            parent,
-            name.to_str().expect("Could not convert OsStr"),
-            _mode,
-            _flags,
+            "create(parent={}, name={:?}, mode={:#o}, flags={:#b})",
+            parent, name, mode, flags,
         );
-        let result = self.fs_create(req, parent, name, FileType::RegularFile, 0, 0);
+        let caller = Caller::from(req);
+        let result = self.fs_create(&caller, parent, name, FileType::RegularFile, mode, flags);
         match result {
             Ok(attr) => {
                 let ttl = Timespec::new(1, 0);
                 let generation = 0; // TODO: Figure out what this is
-                let fh = attr.ino; // TODO: Generate unique file handles
+                let fh = self.open_handle(attr.ino, flags);
                 reply.created(&ttl, &attr, generation, fh, 0);
             }
-            Err(_) => reply.error(ENFILE),
+            Err(err) => reply.error(errno(&err, ENFILE)),
         }
     }
 
     fn mkdir(&mut self, req: &Request, parent: u64, name: &OsStr, mode: u32, reply: ReplyEntry) {
         println!("mkdir()");
-        let result = self.fs_create(req, parent, name, FileType::Directory, mode, 0);
+        let result = self.fs_create(
+            &Caller::from(req),
+            parent,
+            name,
+            FileType::Directory,
+            mode,
+            0,
+        );
         match result {
             Ok(attr) => {
                 let ttl = Timespec::new(1, 0);
                 let generation = 0; // TODO: Figure out what this is
                 reply.entry(&ttl, &attr, generation);
             }
-            Err(_) => reply.error(ENFILE),
+            Err(err) => reply.error(errno(&err, ENFILE)),
         }
     }
+
+    fn symlink(
+        &mut self,
+        req: &Request,
+        parent: u64,
+        name: &OsStr,
+        link: &Path,
+        reply: ReplyEntry,
+    ) {
+        println!(
+            "symlink(parent={}, name={:?}, link={:?})",
+            parent, name, link
+        );
+        let result = self.fs_symlink(&Caller::from(req), parent, name, link);
+        match result {
+            Ok(attr) => {
+                let ttl = Timespec::new(1, 0);
+                reply.entry(&ttl, &attr, 0);
+            }
+            Err(err) => reply.error(errno(&err, ENFILE)),
+        }
+    }
+
     fn release(
         &mut self,
         _req: &Request,
-        _ino: u64,
-        _fh: u64,
+        ino: u64,
+        fh: u64,
         _flags: u32,
         _lock_owner: u64,
         _flush: bool,
         reply: ReplyEmpty,
     ) {
-        println!("release()");
-        reply.ok();
+        println!("release(ino={}, fh={})", ino, fh);
+        match self.fs_release(ino, fh) {
+            Ok(()) => reply.ok(),
+            Err(err) => reply.error(errno(&err, EIO)),
+        }
     }
 
-    fn rmdir(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+    fn releasedir(&mut self, _req: &Request, ino: u64, fh: u64, _flags: u32, reply: ReplyEmpty) {
+        println!("releasedir(ino={}, fh={})", ino, fh);
+        match self.fs_release(ino, fh) {
+            Ok(_) => reply.ok(),
+            Err(err) => reply.error(errno(&err, EIO)),
+        }
+    }
+
+    fn rmdir(&mut self, req: &Request, parent: u64, name: &OsStr, reply: ReplyEmpty) {
         println!("rmdir()");
-        match self.fs_delete(parent, name) {
+        match self.fs_delete(&Caller::from(req), parent, name, true) {
+            Ok(()) => reply.ok(),
+            Err(err) => reply.error(errno(&err, EIO)),
+        };
+    }
+
+    fn rename(
+        &mut self,
+        req: &Request,
+        parent: u64,
+        name: &OsStr,
+        newparent: u64,
+        newname: &OsStr,
+        reply: ReplyEmpty,
+    ) {
+        println!(
+            "rename(parent={}, name={:?}, newparent={}, newname={:?})",
+            parent, name, newparent, newname
+        );
+        match self.fs_rename(&Caller::from(req), parent, name, newparent, newname) {
             Ok(()) => reply.ok(),
-            Err(_) => reply.error(ENOENT),
+            Err(err) => reply.error(errno(&err, EIO)),
         };
     }
 
-    fn unlink(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+    fn unlink(&mut self, req: &Request, parent: u64, name: &OsStr, reply: ReplyEmpty) {
         println!("unlink()");
-        match self.fs_delete(parent, name) {
+        match self.fs_delete(&Caller::from(req), parent, name, false) {
             Ok(()) => reply.ok(),
-            Err(_) => reply.error(ENOENT),
+            Err(err) => reply.error(errno(&err, EIO)),
+        };
+    }
+
+    fn link(
+        &mut self,
+        req: &Request,
+        ino: u64,
+        newparent: u64,
+        newname: &OsStr,
+        reply: ReplyEntry,
+    ) {
+        println!(
+            "link(ino={}, newparent={}, newname={:?})",
+            ino, newparent, newname
+        );
+        match self.fs_link(&Caller::from(req), ino, newparent, newname) {
+            Ok(attr) => {
+                let ttl = Timespec::new(1, 0);
+                reply.entry(&ttl, &attr, 0);
+            }
+            Err(err) => reply.error(errno(&err, EIO)),
         };
     }
 }
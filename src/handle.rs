@@ -0,0 +1,169 @@
+use std::collections::HashMap;
+
+use failure::Error;
+use libc::{c_int, EBADF, O_ACCMODE, O_APPEND, O_RDONLY, O_WRONLY};
+
+use common::constants::WRITE_BUFFER_SIZE;
+use common::errno::Errno;
+
+/// Contiguous writes that haven't been handed to the block pool yet.
+pub struct WriteBuffer {
+    pub offset: u64,
+    pub data: Vec<u8>,
+}
+
+impl WriteBuffer {
+    fn end(&self) -> u64 {
+        self.offset + self.data.len() as u64
+    }
+
+    fn overlaps(&self, offset: u64, end: u64) -> bool {
+        self.offset < end && offset < self.end()
+    }
+}
+
+/// State for one `open` of an inode, released with it.
+pub struct Handle {
+    pub ino: u64,
+    pub flags: c_int,
+    buffer: Option<WriteBuffer>,
+    /// Released with a write still pending, which the next flush retries.
+    released: bool,
+}
+
+impl Handle {
+    pub fn readable(&self) -> bool {
+        self.flags & O_ACCMODE != O_WRONLY
+    }
+
+    pub fn writable(&self) -> bool {
+        self.flags & O_ACCMODE != O_RDONLY
+    }
+
+    pub fn append(&self) -> bool {
+        self.flags & O_APPEND != 0
+    }
+
+    /// Whether a write at `offset` follows on from a buffer with room left.
+    fn continues(&self, offset: u64) -> bool {
+        match self.buffer {
+            Some(ref buffer) => {
+                buffer.end() == offset && (buffer.data.len() as u64) < WRITE_BUFFER_SIZE
+            }
+            None => true,
+        }
+    }
+
+    /// Adds a write that `continues` the buffer, or starts it.
+    pub fn buffer(&mut self, offset: u64, data: &[u8]) {
+        debug_assert!(self.continues(offset));
+        match self.buffer {
+            Some(ref mut buffer) => buffer.data.extend_from_slice(data),
+            None => {
+                self.buffer = Some(WriteBuffer {
+                    offset,
+                    data: data.to_vec(),
+                })
+            }
+        }
+    }
+
+    pub fn take_buffer(&mut self) -> Option<WriteBuffer> {
+        self.buffer.take()
+    }
+}
+
+#[derive(Default)]
+pub struct HandleTable {
+    handles: HashMap<u64, Handle>,
+    next_fh: u64,
+}
+
+impl HandleTable {
+    pub fn open(&mut self, ino: u64, flags: c_int) -> u64 {
+        self.next_fh += 1;
+        let handle = Handle {
+            ino,
+            flags,
+            buffer: None,
+            released: false,
+        };
+        self.handles.insert(self.next_fh, handle);
+        self.next_fh
+    }
+
+    /// The handle `fh`, as long as it was opened for `ino`.
+    pub fn get_mut(&mut self, ino: u64, fh: u64) -> Result<&mut Handle, Error> {
+        match self.handles.get_mut(&fh) {
+            Some(handle) if handle.ino == ino && !handle.released => Ok(handle),
+            _ => Err(Errno(EBADF).into()),
+        }
+    }
+
+    /// Closes `fh`. A write still pending on it stays until it is written out.
+    pub fn release(&mut self, ino: u64, fh: u64) -> Result<(), Error> {
+        let handle = self.get_mut(ino, fh)?;
+        if handle.buffer.is_some() {
+            handle.released = true;
+        } else {
+            self.handles.remove(&fh);
+        }
+        Ok(())
+    }
+
+    /// The handles with a pending write, on `ino` or on any inode.
+    pub fn buffered(&self, ino: Option<u64>) -> Vec<u64> {
+        self.handles
+            .iter()
+            .filter(|(_, handle)| handle.buffer.is_some())
+            .filter(|(_, handle)| ino.map_or(true, |ino| handle.ino == ino))
+            .map(|(fh, _)| *fh)
+            .collect()
+    }
+
+    /// Handles to write out before `fh` can buffer `offset..end` of `ino`.
+    pub fn conflicting(&self, ino: u64, fh: u64, offset: u64, end: u64) -> Vec<u64> {
+        self.handles
+            .iter()
+            .filter(|(_, handle)| handle.ino == ino)
+            .filter(|(other, handle)| match handle.buffer {
+                Some(_) if **other == fh => !handle.continues(offset),
+                Some(ref buffer) => buffer.overlaps(offset, end),
+                None => false,
+            })
+            .map(|(fh, _)| *fh)
+            .collect()
+    }
+
+    /// Takes the pending write of `fh`, with the inode it belongs to.
+    pub fn take_buffer(&mut self, fh: u64) -> Option<(u64, WriteBuffer)> {
+        let handle = self.handles.get_mut(&fh)?;
+        let ino = handle.ino;
+        handle.take_buffer().map(|buffer| (ino, buffer))
+    }
+
+    /// Drops `fh` if it was released with the write that has been written out.
+    pub fn written(&mut self, fh: u64) {
+        if self
+            .handles
+            .get(&fh)
+            .map_or(false, |handle| handle.released)
+        {
+            self.handles.remove(&fh);
+        }
+    }
+
+    /// Puts back a pending write of `fh` that couldn't be written out.
+    pub fn restore_buffer(&mut self, fh: u64, buffer: WriteBuffer) {
+        if let Some(handle) = self.handles.get_mut(&fh) {
+            handle.buffer = Some(buffer);
+        }
+    }
+
+    /// Whether any handle is still open on `ino`.
+    pub fn is_open(&self, ino: u64) -> bool {
+        self.handles
+            .values()
+            .any(|handle| handle.ino == ino && !handle.released)
+    }
+}
@@ -2,6 +2,10 @@
 #![feature(extern_prelude)]
 #![feature(custom_attribute)]
 
+extern crate argon2;
+extern crate base64;
+extern crate blake3;
+extern crate chacha20poly1305;
 extern crate failure;
 extern crate fuse;
 extern crate hyper;
@@ -11,6 +15,7 @@ extern crate reqwest;
 extern crate serde;
 #[macro_use]
 extern crate serde_derive;
+extern crate serde_cbor;
 #[macro_use]
 extern crate serde_json;
 extern crate time;
@@ -24,25 +29,85 @@ extern crate lazy_static;
 extern crate zstd;
 
 mod block;
+mod chunk;
 mod common;
+mod config;
+mod crypto;
 mod entry;
 mod fsapi;
+mod handle;
 mod messenger;
 mod messengerfs;
+mod schema;
+mod storage;
+mod superblock;
+#[cfg(test)]
+mod tests;
 
+use std::env;
 use std::ffi::OsStr;
 use std::fs;
 use std::path::PathBuf;
+use std::process;
 
+use config::FsConfig;
+use crypto::CryptoBackend;
+use messenger::mock::MockServer;
+use messenger::session::Session;
 use messengerfs::MessengerFS;
 
+fn session() -> Session {
+    if env::var("MESSENGER_MOCK").is_err() {
+        return Session::default();
+    }
+    MockServer::start()
+        .expect("Could not start mock messenger server")
+        .session()
+}
+
 fn main() {
-    let fs = MessengerFS::new();
+    let mut config = FsConfig::from_env();
+    let default_permissions = config.default_permissions;
+    let command = env::args().nth(1);
+    let encrypted = config.passphrase.is_some();
+    let mut backend = CryptoBackend::new(session(), config.passphrase.take());
+    if command.as_ref().map(String::as_str) == Some("encrypt") {
+        backend.accept_plaintext();
+    }
+    let fs = if encrypted {
+        MessengerFS::new_encrypted(backend, config)
+    } else {
+        MessengerFS::new(backend, config)
+    };
+    let mut fs = fs.unwrap_or_else(|err| {
+        eprintln!("Could not restore filesystem: {}", err);
+        process::exit(1);
+    });
+    match command.as_ref().map(String::as_str) {
+        Some("compact") => {
+            fs.fs_compact().expect("Could not compact filesystem");
+            return;
+        }
+        // Sealing every plaintext block is what rekeying does anyway
+        Some("rekey") | Some("encrypt") => {
+            fs.fs_rekey().expect("Could not re-encrypt filesystem");
+            return;
+        }
+        Some("passwd") => {
+            let passphrase = env::var("MESSENGERFS_NEW_PASSPHRASE")
+                .expect("MESSENGERFS_NEW_PASSPHRASE must be set");
+            fs.fs_passwd(passphrase)
+                .expect("Could not change passphrase");
+            return;
+        }
+        _ => {}
+    }
+    let mut options = vec!["-o", "noappledouble", "allow_other"];
+    if default_permissions {
+        options.extend(&["-o", "default_permissions"]);
+    }
+    let options = options.iter().map(|o| o.as_ref()).collect::<Vec<&OsStr>>();
     let _ = fs::remove_dir_all("./fs/");
     fs::create_dir_all("./fs/").expect("Could not create mount directory");
-    let options = ["-o", "noappledouble", "allow_other"]
-        .iter()
-        .map(|o| o.as_ref())
-        .collect::<Vec<&OsStr>>();
     fuse::mount(fs, &PathBuf::from("./fs/"), &options).expect("Could not mount filesystem");
 }
@@ -7,7 +7,6 @@ pub struct Credentials {
 }
 
 impl Credentials {
-    #[allow(unused)]
     pub fn new(username: String, password: String) -> Self {
         Self { username, password }
     }
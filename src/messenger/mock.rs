@@ -0,0 +1,289 @@
+use std::cmp::max;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use failure::{err_msg, Error};
+use hyper::rt::{self, Future, Stream};
+use hyper::service::service_fn;
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use serde_json::Value;
+
+use messenger::config::Config;
+use messenger::credentials::Credentials;
+use messenger::model::*;
+use messenger::session::Session;
+
+const MOCK_FBID: &str = "100000000000000";
+
+type ResponseFuture = Box<dyn Future<Item = Response<Body>, Error = hyper::Error> + Send>;
+
+/// In-memory stand-in for `messenger-rpc`.
+pub struct MockServer {
+    addr: SocketAddr,
+}
+
+struct MockState {
+    addr: Option<SocketAddr>,
+    threads: HashMap<String, Vec<Message>>,
+    blobs: HashMap<String, Vec<u8>>,
+    next_id: u64,
+    timestamp: u64,
+}
+
+impl MockServer {
+    pub fn start() -> Result<Self, Error> {
+        let state = Arc::new(Mutex::new(MockState::new()));
+        let service_state = state.clone();
+        let server = Server::try_bind(&([127, 0, 0, 1], 0).into())?.serve(move || {
+            let state = service_state.clone();
+            service_fn(move |req| handle(&state, req))
+        });
+        let addr = server.local_addr();
+        state.lock().expect("Could not acquire mock lock").addr = Some(addr);
+        thread::spawn(move || {
+            rt::run(server.map_err(|err| println!("Mock server error: {}", err)));
+        });
+        Ok(Self { addr })
+    }
+
+    pub fn host(&self) -> String {
+        self.addr.ip().to_string()
+    }
+
+    pub fn port(&self) -> String {
+        self.addr.port().to_string()
+    }
+
+    /// A new session logged in to this server.
+    pub fn session(&self) -> Session {
+        let config = Config {
+            host: self.host(),
+            port: self.port(),
+            ..Config::default()
+        };
+        let credentials = Credentials::new("mock".to_string(), "mock".to_string());
+        Session::with_config(config, credentials)
+    }
+}
+
+fn handle(state: &Arc<Mutex<MockState>>, req: Request<Body>) -> ResponseFuture {
+    let method = req.method().clone();
+    let path = req.uri().path().to_string();
+    match (&method, path.as_str()) {
+        (&Method::POST, "/") => {
+            let state = state.clone();
+            Box::new(req.into_body().concat2().map(move |body| {
+                let mut state = state.lock().expect("Could not acquire mock lock");
+                Response::new(Body::from(state.rpc(&body)))
+            }))
+        }
+        (&Method::GET, path) if path.starts_with("/attachment/") => {
+            let state = state.lock().expect("Could not acquire mock lock");
+            let id = &path["/attachment/".len()..];
+            let response = if state.blobs.contains_key(id) {
+                Response::new(Body::from(state.redirect_page(id)))
+            } else {
+                not_found()
+            };
+            Box::new(rt::lazy(move || Ok(response)))
+        }
+        (&Method::GET, path) if path.starts_with("/blob/") => {
+            let state = state.lock().expect("Could not acquire mock lock");
+            let response = match state.blobs.get(&path["/blob/".len()..]) {
+                Some(blob) => Response::new(Body::from(blob.clone())),
+                None => not_found(),
+            };
+            Box::new(rt::lazy(move || Ok(response)))
+        }
+        _ => Box::new(rt::lazy(|| Ok(not_found()))),
+    }
+}
+
+fn not_found() -> Response<Body> {
+    let mut response = Response::new(Body::empty());
+    *response.status_mut() = StatusCode::NOT_FOUND;
+    response
+}
+
+impl MockState {
+    fn new() -> Self {
+        Self {
+            addr: None,
+            threads: HashMap::new(),
+            blobs: HashMap::new(),
+            next_id: 0,
+            timestamp: 0,
+        }
+    }
+
+    fn rpc(&mut self, body: &[u8]) -> String {
+        let request: Value = match serde_json::from_slice(body) {
+            Ok(request) => request,
+            Err(err) => return rpc_error(Value::Null, -32700, &err.to_string()),
+        };
+        let id = request["id"].clone();
+        let method = request["method"].as_str().unwrap_or("");
+        let params = request["params"].as_array().cloned().unwrap_or_default();
+        match self.call(method, &params) {
+            Ok(result) => json!({ "jsonrpc": "2.0", "result": result, "id": id }).to_string(),
+            Err(err) => rpc_error(id, -32601, &err.to_string()),
+        }
+    }
+
+    fn call(&mut self, method: &str, params: &[Value]) -> Result<Value, Error> {
+        let param = |idx: usize| params.get(idx).cloned().unwrap_or(Value::Null);
+        match method {
+            "ping" => Ok(param(0)),
+            "authenticate" => Ok(json!("Login success")),
+            "my_fbid" => Ok(json!(MOCK_FBID)),
+            "user_info" => Ok(serde_json::to_value(mock_user())?),
+            "search" => Ok(json!("response")),
+            "message" => {
+                let body = string_param(&param(0))?;
+                let thread_id = string_param(&param(1))?;
+                let sent = self.post(thread_id, body, Vec::new());
+                Ok(serde_json::to_value(sent)?)
+            }
+            "attachment" => {
+                let data = string_param(&param(0))?
+                    .chars()
+                    .map(|c| c as u8)
+                    .collect::<Vec<_>>();
+                let thread_id = string_param(&param(1))?;
+                let attachment = self.store_blob(data);
+                let sent = self.post(thread_id, String::new(), vec![attachment]);
+                Ok(serde_json::to_value(sent)?)
+            }
+            "history" => {
+                let thread_id = string_param(&param(0))?;
+                let amount = param(1).as_u64().unwrap_or(0) as usize;
+                let before = param(2).as_str().and_then(|ts| ts.parse::<u64>().ok());
+                Ok(serde_json::to_value(
+                    self.history(&thread_id, amount, before),
+                )?)
+            }
+            _ => Err(err_msg(format!("Method not found: {}", method))),
+        }
+    }
+
+    fn next_id(&mut self) -> String {
+        self.next_id += 1;
+        self.next_id.to_string()
+    }
+
+    /// Millisecond timestamps that never repeat, so history paging is exact.
+    fn next_timestamp(&mut self) -> u64 {
+        let now = time::now().to_timespec();
+        let millis = now.sec as u64 * 1000 + now.nsec as u64 / 1_000_000;
+        self.timestamp = max(self.timestamp + 1, millis);
+        self.timestamp
+    }
+
+    fn post(
+        &mut self,
+        thread_id: String,
+        body: String,
+        attachments: Vec<Attachment>,
+    ) -> MessageSent {
+        let message_id = format!("mid.{}", self.next_id());
+        let timestamp = self.next_timestamp();
+        let message = Message {
+            mtype: "message".to_string(),
+            attachments,
+            body,
+            is_group: false,
+            message_id: message_id.clone(),
+            sender_id: MOCK_FBID.to_string(),
+            thread_id: thread_id.clone(),
+            timestamp: timestamp.to_string(),
+            is_unread: false,
+            is_sponsored: false,
+        };
+        self.threads
+            .entry(thread_id.clone())
+            .or_default()
+            .push(message);
+        MessageSent {
+            thread_id,
+            message_id,
+            timestamp,
+        }
+    }
+
+    fn store_blob(&mut self, data: Vec<u8>) -> Attachment {
+        let id = self.next_id();
+        let addr = self.addr.expect("Mock server address not set");
+        let attachment = Attachment {
+            name: "block".to_string(),
+            atype: "file".to_string(),
+            filename: "block".to_string(),
+            id: id.clone(),
+            url: format!("http://{}/attachment/{}", addr, id),
+            is_malicious: false,
+            content_type: "application/octet-stream".to_string(),
+            mime_type: "application/octet-stream".to_string(),
+            file_size: data.len() as i32,
+        };
+        self.blobs.insert(id, data);
+        attachment
+    }
+
+    /// Shaped like Messenger's attachment page, with the url escaped.
+    fn redirect_page(&self, id: &str) -> String {
+        let addr = self.addr.expect("Mock server address not set");
+        let url = format!("http://{}/blob/{}", addr, id).replace("/", r"\/");
+        format!(
+            "<html><script>document.location.replace(\"{}\");</script></html>",
+            url
+        )
+    }
+
+    /// Oldest first, like `getThreadHistory`, ending just before `before`.
+    fn history(&self, thread_id: &str, amount: usize, before: Option<u64>) -> Vec<Message> {
+        let messages = match self.threads.get(thread_id) {
+            Some(messages) => messages,
+            None => return Vec::new(),
+        };
+        let end = match before {
+            Some(before) => messages
+                .iter()
+                .position(|message| message.timestamp.parse::<u64>().unwrap_or(0) >= before)
+                .unwrap_or(messages.len()),
+            None => messages.len(),
+        };
+        let start = end.saturating_sub(amount);
+        messages[start..end].to_vec()
+    }
+}
+
+fn string_param(value: &Value) -> Result<String, Error> {
+    value
+        .as_str()
+        .map(|value| value.to_string())
+        .ok_or_else(|| err_msg("Expected string parameter"))
+}
+
+fn rpc_error(id: Value, code: i64, message: &str) -> String {
+    json!({
+        "jsonrpc": "2.0",
+        "error": { "code": code, "message": message },
+        "id": id,
+    })
+    .to_string()
+}
+
+fn mock_user() -> User {
+    User {
+        name: "Mock User".to_string(),
+        first_name: "Mock".to_string(),
+        vanity: "mock.user".to_string(),
+        thumb_src: String::new(),
+        profile_url: String::new(),
+        gender: 0,
+        utype: "user".to_string(),
+        is_friend: false,
+        is_birthday: false,
+    }
+}
@@ -1,4 +1,5 @@
 pub(crate) mod config;
 pub(crate) mod credentials;
+pub(crate) mod mock;
 pub(crate) mod model;
 pub mod session;
@@ -1,5 +1,3 @@
-use std::sync::Mutex;
-
 use failure::{err_msg, Error};
 use jsonrpc_client_http::{HttpHandle, HttpTransport};
 use regex::Regex;
@@ -8,9 +6,14 @@ use common::constants::{MAX_MESSAGE_FETCH, MESSAGE_BATCH_SIZE};
 use messenger::config::Config;
 use messenger::credentials::Credentials;
 use messenger::model::*;
+use storage::{Candidate, StorageBackend};
+
+/// Starts a metadata message, followed by the snapshot in base64.
+const METADATA_PREFIX: &str = "mfs-b64:";
 
 lazy_static! {
-    pub static ref SESSION: Mutex<Session> = Mutex::new(Session::default());
+    static ref REDIRECT_URL: Regex =
+        Regex::new("document.location.replace\\(\"(?P<url>.*?)\"\\);").unwrap();
 }
 
 #[allow(unused)]
@@ -47,10 +50,13 @@ impl Default for Session {
 
 impl Session {
     pub fn new(credentials: Credentials) -> Self {
+        Self::with_config(Config::default(), credentials)
+    }
+
+    pub fn with_config(config: Config, credentials: Credentials) -> Self {
         let transport = HttpTransport::new()
             .standalone()
             .expect("Could not get http transport");
-        let config = Config::default();
         let addr = format!("http://{}:{}/", config.host, config.port);
         let handle = transport
             .handle(&addr)
@@ -109,10 +115,30 @@ impl Session {
         Ok(resp)
     }
 
-    pub fn get_latest_message(&mut self) -> Result<Message, Error> {
+    /// The newest message before `before` that `matches`.
+    pub fn find_message<F>(
+        &mut self,
+        before: Option<&str>,
+        matches: F,
+    ) -> Result<Option<Message>, Error>
+    where
+        F: Fn(&Message) -> bool,
+    {
         let fbid = self.get_self_thread_id()?;
-        let history = self.client.history(fbid, 1, None).call().unwrap();
-        Ok(history[0].clone())
+        let mut timestamp = before.map(str::to_string);
+        loop {
+            let history = self
+                .client
+                .history(fbid.clone(), MESSAGE_BATCH_SIZE, timestamp.take())
+                .call()?;
+            if history.is_empty() {
+                return Ok(None);
+            }
+            timestamp = Some(history[0].timestamp.clone());
+            if let Some(message) = history.into_iter().rev().find(&matches) {
+                return Ok(Some(message));
+            }
+        }
     }
 
     pub fn get_message(&mut self, message_id: String) -> Result<Message, Error> {
@@ -143,11 +169,58 @@ impl Session {
 
     pub fn get_attachment(&mut self, url: &str, buf: &mut Vec<u8>) -> Result<u64, Error> {
         let redirect_text = reqwest::get(url)?.text()?;
-        let re = Regex::new("document.location.replace\\(\"(?P<url>.*?)\"\\);").unwrap();
-        let captured = re.captures(&redirect_text).unwrap();
+        let captured = REDIRECT_URL.captures(&redirect_text).unwrap();
         let raw_url = captured["url"].to_string();
         let url = raw_url.replace(r"\/", "/");
         println!("{}", url);
         Ok(reqwest::get(&url)?.copy_to(buf)?)
     }
 }
+
+impl StorageBackend for Session {
+    fn put_blob(&mut self, data: &[u8]) -> Result<String, Error> {
+        let encoded = data.iter().map(|byte| *byte as char).collect();
+        let resp = self.attachment(encoded, None)?;
+        let message = self.get_message(resp.message_id)?;
+        match message.attachments.first() {
+            Some(attachment) => Ok(attachment.url.clone()),
+            None => Err(err_msg("Sent message has no attachment")),
+        }
+    }
+
+    fn get_blob(&mut self, url: &str) -> Result<Vec<u8>, Error> {
+        let mut data = Vec::new();
+        self.get_attachment(url, &mut data)?;
+        Ok(data)
+    }
+
+    fn post_metadata(&mut self, metadata: &[u8]) -> Result<(), Error> {
+        let encoded = format!("{}{}", METADATA_PREFIX, base64::encode(metadata));
+        self.message(encoded, None)?;
+        Ok(())
+    }
+
+    /// Old snapshots are the message text itself.
+    fn fetch_metadata(&mut self, before: Option<&str>) -> Result<Option<Candidate>, Error> {
+        let message = self.find_message(before, |message| {
+            message.attachments.is_empty() && !message.body.is_empty()
+        })?;
+        Ok(message.map(|message| {
+            let body = message.body;
+            let decoded = if body.starts_with(METADATA_PREFIX) {
+                base64::decode(&body[METADATA_PREFIX.len()..]).ok()
+            } else {
+                None
+            };
+            Candidate {
+                metadata: Ok(decoded.unwrap_or_else(|| body.into_bytes())),
+                cursor: message.timestamp,
+            }
+        }))
+    }
+
+    fn has_blobs(&mut self) -> Result<bool, Error> {
+        let message = self.find_message(None, |message| !message.attachments.is_empty())?;
+        Ok(message.is_some())
+    }
+}
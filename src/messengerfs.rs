@@ -1,61 +1,423 @@
-use std::cmp::{max, min};
-use std::collections::BTreeMap;
+use std::cmp::min;
+use std::collections::{BTreeMap, HashMap};
 use std::ffi::OsStr;
+use std::mem;
+use std::path::Path;
 use std::result::Result;
 
 use failure::{err_msg, Error};
-use fuse::{FileAttr, FileType, Request};
+use fuse::{FileAttr, FileType};
+use libc::{
+    c_int, EACCES, EBADF, EEXIST, EINVAL, EISDIR, ENOENT, ENOTDIR, ENOTEMPTY, EPERM, O_ACCMODE,
+    O_RDWR, O_TRUNC, O_WRONLY, R_OK, W_OK, XATTR_CREATE, XATTR_REPLACE, X_OK,
+};
+use time::Timespec;
 
-use block::BlockPool;
-use common::constants::{MEGABYTES, USER_DIR, ZSTD_COMPRESSION_LEVEL};
-use common::tree::{Node, Tree};
-use entry::FileSystemEntry;
-use messenger::session::SESSION;
+use block::{Block, BlockPool, DataLoc, PoolDelta};
+use common::constants::{
+    CHECKPOINT_INTERVAL, COMPACTION_INTERVAL, MAX_INLINE_METADATA, MEGABYTES, METADATA_PART_SIZE,
+    REKEY_BATCH_SIZE, STATFS_BLOCK_SIZE, ZSTD_COMPRESSION_LEVEL,
+};
+use common::errno::{Errno, ENOATTR};
+use common::permission::{may_delete, permits, Caller};
+use common::tree::{Change, Tree};
+use config::FsConfig;
+use crypto::{CryptoBackend, KeyError, NameCipher};
+use entry::{EncodeFileAttr, EncodeFileType, EncodeTimespec, FileSystemEntry};
+use handle::{HandleTable, WriteBuffer};
+use schema::{self, SCHEMA_VERSION};
+use storage::StorageBackend;
+use superblock::{self, Kind, MissingSuperblock, Pointer};
 
+/// Usage as reported to `statfs`, in `STATFS_BLOCK_SIZE` units.
+pub struct Statfs {
+    pub blocks: u64,
+    pub free: u64,
+    pub files: u64,
+    pub ffree: u64,
+}
+
+/// The attributes `setattr` was asked to change.
+#[derive(Default)]
+pub struct SetAttr {
+    pub mode: Option<u32>,
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+    pub size: Option<u64>,
+    pub atime: Option<Timespec>,
+    pub mtime: Option<Timespec>,
+    pub crtime: Option<Timespec>,
+    pub chgtime: Option<Timespec>,
+    pub flags: Option<u32>,
+}
+
+/// What a compaction moved, kept until it is committed.
+struct Compaction {
+    moved: Vec<(u64, Vec<DataLoc>)>,
+    chunks: Vec<(u64, DataLoc)>,
+    retired: HashMap<u64, Block>,
+}
+
+/// What a commit changed since the previous superblock.
 #[derive(Serialize, Deserialize)]
-pub struct MessengerFS {
+struct JournalEntry {
+    #[serde(default)]
+    schema: u32,
+    changes: Vec<Change<FileSystemEntry>>,
+    /// Entries without their extents, which go in `extents`.
+    entries: Vec<(u64, FileSystemEntry)>,
+    extents: Vec<ExtentDelta>,
+    pool: PoolDelta,
+    inode: u64,
+    size: usize,
+}
+
+/// The extents of a file from `from` on, replacing the ones it had there.
+#[derive(Serialize, Deserialize)]
+struct ExtentDelta {
+    ino: u64,
+    from: usize,
+    locs: Vec<DataLoc>,
+}
+
+/// A decoded superblock.
+enum Snapshot {
+    Checkpoint(Detached),
+    Journal(u64, JournalEntry),
+}
+
+/// The filesystem as restored, before it is given a backend.
+type Detached = MessengerFS<()>;
+
+#[derive(Serialize, Deserialize)]
+#[serde(bound(deserialize = "S: Default"))]
+pub struct MessengerFS<S> {
+    #[serde(skip)]
+    pub backend: S,
+    #[serde(skip)]
+    pub config: FsConfig,
+    #[serde(skip)]
+    pub handles: HandleTable,
+    #[serde(skip)]
+    names: Option<NameCipher>,
+    /// Sequence number of the last superblock written or restored.
+    #[serde(skip)]
+    seq: u64,
+    /// Sequence number of the last checkpoint.
+    #[serde(skip)]
+    checkpoint: u64,
+    /// Set when the changes can't be journaled.
+    #[serde(skip)]
+    checkpoint_due: bool,
+    /// Blocks uploaded since the last superblock.
+    #[serde(skip)]
+    synced: Vec<u64>,
+    /// Newest chunk as of the last superblock.
+    #[serde(skip)]
+    chunk_mark: u64,
+    /// First extent of each file that changed since the last superblock.
+    #[serde(skip)]
+    extents: HashMap<u64, usize>,
+    /// Sequence number of the last superblock that compacted blocks.
+    #[serde(skip)]
+    compacted: Option<u64>,
     pub inode: u64,
-    pub inodes: BTreeMap<String, u64>,
     pub fs: Tree<FileSystemEntry>,
     pub blocks: BlockPool,
     pub size: usize,
+    /// Layout of the persisted metadata, see `schema`.
+    #[serde(default)]
+    schema: u32,
+    /// Whether the names in `fs` are stored encrypted.
+    #[serde(default)]
+    pub encrypted_names: bool,
 }
 
-impl MessengerFS {
-    pub fn new() -> Self {
-        Self::restore().unwrap_or_else(|err| {
-            println!(
-                "Could not restore from messenger: {}\nCreating new FS...",
-                err
-            );
-            let inodes = BTreeMap::new();
-            let fs = Tree::new();
-            let blocks = BlockPool::new(4, 5 * MEGABYTES);
-
-            let mut fs = Self {
-                inode: 1,
-                inodes,
-                fs,
-                size: 0,
-                blocks,
+impl Detached {
+    fn attach<S>(self, backend: S, config: FsConfig) -> MessengerFS<S> {
+        MessengerFS {
+            backend,
+            config,
+            handles: self.handles,
+            names: self.names,
+            seq: self.seq,
+            checkpoint: self.checkpoint,
+            checkpoint_due: self.checkpoint_due,
+            synced: self.synced,
+            chunk_mark: self.chunk_mark,
+            extents: self.extents,
+            compacted: self.compacted,
+            inode: self.inode,
+            fs: self.fs,
+            blocks: self.blocks,
+            size: self.size,
+            schema: self.schema,
+            encrypted_names: self.encrypted_names,
+        }
+    }
+}
+
+impl<S: StorageBackend> MessengerFS<S> {
+    /// Creates a new filesystem only if nothing was ever stored.
+    pub fn new(backend: S, config: FsConfig) -> Result<Self, Error> {
+        let fs = Self::open(backend, config)?;
+        if fs.encrypted_names || fs.config.encrypt_names {
+            return Err(KeyError("Encrypted names need a passphrase").into());
+        }
+        Ok(fs)
+    }
+
+    fn open(mut backend: S, config: FsConfig) -> Result<Self, Error> {
+        let fs = match Self::restore(&mut backend)? {
+            Some(fs) => fs.attach(backend, config),
+            None => {
+                println!("No snapshot found\nCreating new FS...");
+                let fs = Tree::new();
+                let blocks = BlockPool::new(4, 5 * MEGABYTES);
+
+                let mut fs = Self {
+                    backend,
+                    config,
+                    handles: HandleTable::default(),
+                    names: None,
+                    seq: 0,
+                    checkpoint: 0,
+                    checkpoint_due: true,
+                    synced: Vec::new(),
+                    chunk_mark: 0,
+                    extents: HashMap::new(),
+                    compacted: None,
+                    inode: 1,
+                    fs,
+                    size: 0,
+                    blocks,
+                    schema: SCHEMA_VERSION,
+                    encrypted_names: false,
+                };
+                fs.create_root()?;
+                fs
+            }
+        };
+        Ok(fs)
+    }
+
+    /// The newest readable checkpoint and its journal, `None` if never stored.
+    pub fn restore(backend: &mut S) -> Result<Option<Detached>, Error> {
+        let mut cursor = None;
+        let mut journal = BTreeMap::new();
+        // Why the newest snapshot passed over couldn't be read
+        let mut unreadable = None;
+        while let Some(candidate) = backend.fetch_metadata(cursor.as_ref().map(String::as_str))? {
+            let metadata = match candidate.metadata {
+                Ok(metadata) => Self::read_candidate(backend, metadata)?,
+                Err(err) => Some(Err(err)),
             };
-            fs.create_root();
-            fs
-        })
+            let decoded = metadata
+                .map(|metadata| metadata.and_then(|metadata| Ok((decode(&metadata)?, metadata))));
+            match decoded {
+                Some(Ok((Snapshot::Checkpoint(mut fs), metadata))) => {
+                    backend.accept_metadata(&candidate.cursor)?;
+                    if let Err(err) = fs.replay(journal) {
+                        println!("{}, restoring the checkpoint alone", err);
+                        fs = match decode(&metadata)? {
+                            Snapshot::Checkpoint(fs) => fs,
+                            Snapshot::Journal(..) => return Err(err),
+                        };
+                        fs.checkpoint_due = true;
+                    }
+                    fs.free_unlinked();
+                    fs.recount_blocks();
+                    return Ok(Some(fs));
+                }
+                // Paging backwards, so the first entry seen for a number wins
+                Some(Ok((Snapshot::Journal(seq, entry), _))) => {
+                    journal.entry(seq).or_insert(entry);
+                }
+                Some(Err(err)) => {
+                    println!("Skipping snapshot that can't be read: {}", err);
+                    unreadable.get_or_insert(err);
+                }
+                None => {}
+            }
+            cursor = Some(candidate.cursor);
+        }
+        if let Some(err) = unreadable {
+            return Err(err);
+        }
+        if backend.has_blobs()? {
+            return Err(MissingSuperblock.into());
+        }
+        Ok(None)
     }
 
-    pub fn restore() -> Result<Self, Error> {
-        let last_message = SESSION
-            .lock()
-            .expect("Could not acquire Session lock")
-            .get_latest_message()?;
+    /// Follows pointers; `None` for a chat message.
+    fn read_candidate(
+        backend: &mut S,
+        metadata: Vec<u8>,
+    ) -> Result<Option<Result<Vec<u8>, Error>>, Error> {
+        if !superblock::is_pointer(&metadata) {
+            if !superblock::is_snapshot(&metadata) {
+                return Ok(None);
+            }
+            return Ok(Some(Ok(metadata)));
+        }
+        let pointer = match superblock::decode_pointer(&metadata) {
+            Ok(pointer) => pointer,
+            Err(err) => return Ok(Some(Err(err))),
+        };
+        let mut metadata = Vec::new();
+        for url in &pointer.parts {
+            match backend.get_blob(url) {
+                Ok(part) => metadata.extend(part),
+                Err(err) if err.downcast_ref::<KeyError>().is_some() => return Ok(Some(Err(err))),
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(Some(Ok(metadata)))
+    }
+}
+
+/// Whether `time` is the kernel's stand-in for `UTIME_NOW`.
+fn is_now(time: Timespec) -> bool {
+    (time::now().to_timespec() - time).num_seconds().abs() <= 1
+}
+
+fn decode(metadata: &[u8]) -> Result<Snapshot, Error> {
+    if !superblock::is_superblock(metadata) {
+        // Snapshots from before superblocks are plain JSON
+        let mut fs: Detached = schema::decode_legacy(metadata)?;
+        fs.checkpoint_due = true;
+        return Ok(Snapshot::Checkpoint(fs));
+    }
+    let (header, payload) = superblock::decode(metadata)?;
+    let payload = zstd::decode_all(payload)?;
+    if header.kind == Kind::Journal {
+        return Ok(Snapshot::Journal(
+            header.seq,
+            schema::decode_journal(&payload)?,
+        ));
+    }
+    println!(
+        "Restoring superblock {} (format {})",
+        header.seq, header.version
+    );
+    let (mut fs, upgraded): (Detached, _) = schema::decode_snapshot(&payload)?;
+    // Write it back in the current layout
+    fs.checkpoint_due = upgraded;
+    fs.schema = SCHEMA_VERSION;
+    fs.seq = header.seq;
+    fs.checkpoint = header.seq;
+    fs.chunk_mark = fs.blocks.last_chunk_id();
+    Ok(Snapshot::Checkpoint(fs))
+}
+
+impl<S> MessengerFS<S> {
+    /// Applies the journal entries that follow on from this checkpoint.
+    fn replay(&mut self, mut journal: BTreeMap<u64, JournalEntry>) -> Result<(), Error> {
+        for (seq, entry) in journal.split_off(&(self.checkpoint + 1)) {
+            if seq != self.seq + 1 {
+                println!(
+                    "Journal entry {} is missing, dropping the {} after it",
+                    self.seq + 1,
+                    seq
+                );
+                self.checkpoint_due = true;
+                break;
+            }
+            for change in entry.changes {
+                self.fs.replay(change).map_err(|err| {
+                    err_msg(format!("Journal entry {} doesn't apply: {}", seq, err))
+                })?;
+            }
+            for (ino, entry) in entry.entries {
+                self.fs.replay_entry(ino, |old| {
+                    let data = old.data.take();
+                    *old = entry;
+                    old.data = data;
+                });
+            }
+            for delta in entry.extents {
+                self.fs.replay_entry(delta.ino, |entry| {
+                    let locs = entry.data.get_or_insert_with(Vec::new);
+                    locs.truncate(delta.from);
+                    locs.extend(delta.locs);
+                });
+            }
+            self.blocks.replay(entry.pool);
+            self.inode = entry.inode;
+            self.size = entry.size;
+            self.seq = seq;
+        }
+        if self.seq > self.checkpoint {
+            println!("Replayed journal up to {}", self.seq);
+        }
+        self.chunk_mark = self.blocks.last_chunk_id();
+        Ok(())
+    }
+
+    /// Frees files unlinked while open, as no handle survives a restart.
+    fn free_unlinked(&mut self) {
+        let unlinked: Vec<u64> = self
+            .fs
+            .nodes()
+            .filter(|node| node.entry.attr.nlink == 0)
+            .map(|node| node.entry.attr.ino)
+            .collect();
+        for ino in unlinked {
+            self.free_node(ino);
+        }
+    }
 
-        // TODO: Figure out how to encode this
+    /// Frees an inode no directory entry points to any more, with its data.
+    fn free_node(&mut self, ino: u64) {
+        let node = match self.fs.remove(ino) {
+            Some(node) => node,
+            None => return,
+        };
+        let entry = node.entry;
+        match entry.attr.kind {
+            EncodeFileType::Directory => {
+                if let Some(parent) = node.parent.and_then(|parent| self.fs.get_mut(parent)) {
+                    parent.entry.attr.nlink -= 1;
+                }
+            }
+            EncodeFileType::RegularFile => self.size -= entry.attr.size as usize,
+            _ => {}
+        }
+        if let Some(mut locs) = entry.data {
+            self.blocks.truncate(&mut locs, 0);
+        }
+    }
+
+    fn recount_blocks(&mut self) {
+        let locs = self
+            .fs
+            .nodes()
+            .filter_map(|node| node.entry.data.as_ref())
+            .flat_map(|locs| locs.iter());
+        self.blocks.recount(locs);
+    }
+}
+
+impl<S: StorageBackend> MessengerFS<S> {
+    /// The key `name` is stored under; it must be UTF-8.
+    pub fn encode_name(&self, name: &OsStr) -> Result<String, Error> {
+        let name = name.to_str().ok_or(Errno(EINVAL))?;
+        Ok(match self.names {
+            Some(ref names) => names.encrypt(name),
+            None => name.to_owned(),
+        })
+    }
 
-        Ok(serde_json::from_str(&last_message.body)?)
+    /// The name of a directory entry as the kernel sees it.
+    pub fn decode_name(&self, stored: &str) -> Result<String, Error> {
+        match self.names {
+            Some(ref names) => names.decrypt(stored),
+            None => Ok(stored.to_owned()),
+        }
     }
 
-    pub fn create_root(&mut self) {
+    pub fn create_root(&mut self) -> Result<(), Error> {
         // TODO: Consolidate with fs_create
         let ts = time::now().to_timespec();
         let inode = self.get_next_inode();
@@ -69,15 +431,14 @@ impl MessengerFS {
             crtime: ts,
             kind: FileType::Directory,
             perm: 0o777,
-            nlink: 0,
+            nlink: 2,
             uid: 0,
             gid: 0,
             rdev: 0,
             flags: 0,
         };
-        let root = FileSystemEntry::new("/".to_string(), attr);
-        self.inodes.insert("/".to_owned(), 1);
-        self.fs.add(None, inode, root);
+        let root = FileSystemEntry::new(attr);
+        self.fs.add(None, "/", inode, root)
     }
 
     pub fn get_next_inode(&mut self) -> u64 {
@@ -86,19 +447,164 @@ impl MessengerFS {
         inode
     }
 
+    /// Checks `mask` against the stored attributes of `ino` for the caller.
+    pub fn fs_access(&self, caller: &Caller, ino: u64, mask: c_int) -> Result<(), Error> {
+        let attr = self
+            .fs
+            .get(ino)
+            .ok_or(Errno(ENOENT))?
+            .entry
+            .attr
+            .unmarshal();
+        if permits(&attr, caller.uid, caller.gid, mask) {
+            Ok(())
+        } else {
+            Err(Errno(EACCES).into())
+        }
+    }
+
+    /// `fs_access` unless left to the kernel.
+    pub fn check_access(&self, caller: &Caller, ino: u64, mask: c_int) -> Result<(), Error> {
+        if self.config.default_permissions {
+            return Ok(());
+        }
+        self.fs_access(caller, ino, mask)
+    }
+
+    fn check_delete(&self, caller: &Caller, parent: u64, ino: u64) -> Result<(), Error> {
+        if self.config.default_permissions {
+            return Ok(());
+        }
+        self.check_access(caller, parent, W_OK | X_OK)?;
+        let dir = self.fs.get(parent).unwrap().entry.attr.unmarshal();
+        let attr = self
+            .fs
+            .get(ino)
+            .ok_or(Errno(ENOENT))?
+            .entry
+            .attr
+            .unmarshal();
+        if may_delete(&dir, &attr, caller.uid) {
+            Ok(())
+        } else {
+            Err(Errno(EPERM).into())
+        }
+    }
+
+    /// Setting times to now only needs write access; the rest, ownership.
+    pub fn check_setattr(
+        &self,
+        caller: &Caller,
+        ino: u64,
+        mode: Option<u32>,
+        uid: Option<u32>,
+        gid: Option<u32>,
+        times: &[Option<Timespec>],
+    ) -> Result<(), Error> {
+        if self.config.default_permissions {
+            return Ok(());
+        }
+        let attr = self
+            .fs
+            .get(ino)
+            .ok_or(Errno(ENOENT))?
+            .entry
+            .attr
+            .unmarshal();
+        let root = caller.uid == 0;
+        let owner = root || caller.uid == attr.uid;
+        let chown = uid.map_or(false, |uid| uid != attr.uid);
+        let chgrp = gid.map_or(false, |gid| gid != attr.gid);
+        let touch = times.iter().any(Option::is_some);
+        let now = times.iter().flatten().all(|time| is_now(*time));
+        if (mode.is_some() && !owner)
+            || (chown && !root)
+            || (chgrp && !root && !(owner && gid == Some(caller.gid)))
+            || (touch && !owner && !(now && permits(&attr, caller.uid, caller.gid, W_OK)))
+        {
+            return Err(Errno(EPERM).into());
+        }
+        Ok(())
+    }
+
+    pub fn fs_setattr(
+        &mut self,
+        caller: &Caller,
+        ino: u64,
+        changes: &SetAttr,
+    ) -> Result<FileAttr, Error> {
+        let times = [changes.atime, changes.mtime];
+        self.check_setattr(caller, ino, changes.mode, changes.uid, changes.gid, &times)?;
+        if let Some(size) = changes.size {
+            self.check_access(caller, ino, W_OK)?;
+            self.fs_truncate(ino, size)?;
+        }
+        let entry = &mut self.fs.get_mut(ino).ok_or(Errno(ENOENT))?.entry;
+        let attr = entry.attr.unmarshal();
+        let ctime = if changes.mode.is_some() || changes.uid.is_some() || changes.gid.is_some() {
+            time::now().to_timespec()
+        } else {
+            attr.ctime
+        };
+        let attr = FileAttr {
+            perm: changes
+                .mode
+                .map_or(attr.perm, |mode| (mode & 0o7777) as u16),
+            uid: changes.uid.unwrap_or(attr.uid),
+            gid: changes.gid.unwrap_or(attr.gid),
+            atime: changes.atime.unwrap_or(attr.atime),
+            mtime: changes.mtime.unwrap_or(attr.mtime),
+            crtime: changes.crtime.unwrap_or(attr.crtime),
+            ctime: changes.chgtime.unwrap_or(ctime),
+            flags: changes.flags.unwrap_or(attr.flags),
+            ..attr
+        };
+        entry.attr = EncodeFileAttr::marshal(attr);
+        Ok(attr)
+    }
+
+    /// Resolving a name needs search access to the directory.
+    pub fn fs_lookup(&self, caller: &Caller, parent: u64, name: &OsStr) -> Result<FileAttr, Error> {
+        self.check_access(caller, parent, X_OK)?;
+        let ino = self
+            .fs
+            .lookup(parent, &self.encode_name(name)?)
+            .ok_or(Errno(ENOENT))?;
+        Ok(self
+            .fs
+            .get(ino)
+            .ok_or(Errno(ENOENT))?
+            .entry
+            .attr
+            .unmarshal())
+    }
+
     pub fn fs_create(
         &mut self,
-        req: &Request,
+        caller: &Caller,
         parent: u64,
         name: &OsStr,
         kind: FileType,
-        _mode: u32,
+        mode: u32,
         _flags: u32,
     ) -> Result<FileAttr, Error> {
+        let name = &self.encode_name(name)?;
+        self.check_access(caller, parent, W_OK | X_OK)?;
+        if self.fs.lookup(parent, name).is_some() {
+            return Err(Errno(EEXIST).into());
+        }
+        let parent_node = self.fs.get_mut(parent).ok_or(Errno(ENOENT))?;
+        let nlink = match kind {
+            FileType::Directory => {
+                parent_node.entry.attr.nlink += 1;
+                2
+            }
+            _ => 1,
+        };
+
         // add in new inode
         let inode = self.get_next_inode();
         let ts = time::now().to_timespec();
-        let name = name.to_str().expect("Could not parse os str");
         let attr = FileAttr {
             ino: inode,
             size: 0,
@@ -108,91 +614,714 @@ impl MessengerFS {
             ctime: ts,
             crtime: ts,
             kind,
-            perm: USER_DIR,
-            nlink: 0,
-            uid: req.uid(),
-            gid: req.gid(),
+            perm: (mode & 0o7777) as u16 & !self.config.umask,
+            nlink,
+            uid: caller.uid,
+            gid: caller.gid,
             rdev: 0,
             flags: 0,
         };
-        let new_entry = FileSystemEntry::new(name.to_owned(), attr);
-        self.fs.add(Some(parent), inode, new_entry);
-
-        self.inodes.insert(name.to_owned(), inode);
+        let new_entry = FileSystemEntry::new(attr);
+        self.fs.add(Some(parent), name, inode, new_entry)?;
         Ok(attr)
     }
 
-    pub fn fs_open(&self, ino: u64, _flags: u32) -> Result<u64, Error> {
-        Ok(ino) // TODO: Generate file handles
-    }
-
-    pub fn fs_read(&self, ino: u64, _fh: u64, offset: i64, _size: u32) -> Result<Vec<u8>, Error> {
-        match self.fs.get(ino) {
-            Some(Node { entry, .. }) => {
-                let data_len = entry.attr.size;
-                let start = min(offset as u64, data_len);
-                let mut curr_pos: u64 = 0;
-                let mut data = Vec::new();
-                let mut arena = self.blocks.arena.borrow_mut();
-                for loc in entry.data.as_ref().unwrap() {
-                    if curr_pos + loc.size > start {
-                        let block_start = max(start - curr_pos, 0);
-                        let mut block = arena.get_mut(&loc.block_id).unwrap();
-                        let mut block_data = block.data()[block_start as usize..].to_vec();
-                        data.append(&mut block_data);
-                    }
-                    curr_pos += loc.size;
-                }
-                Ok(data)
-            }
-            None => Err(err_msg("Could not read file")),
+    pub fn fs_symlink(
+        &mut self,
+        caller: &Caller,
+        parent: u64,
+        name: &OsStr,
+        link: &Path,
+    ) -> Result<FileAttr, Error> {
+        let link = link.to_str().ok_or(Errno(EINVAL))?;
+        let attr = self.fs_create(caller, parent, name, FileType::Symlink, 0, 0)?;
+        let node = self.fs.get_mut(attr.ino).unwrap();
+        node.entry.link = Some(link.to_owned());
+        node.entry.attr.size = link.len() as u64;
+        node.entry.attr.perm = 0o777;
+        Ok(node.entry.attr.unmarshal())
+    }
+
+    pub fn fs_readlink(&self, ino: u64) -> Result<Vec<u8>, Error> {
+        let node = self.fs.get(ino).ok_or(Errno(ENOENT))?;
+        match node.entry.link {
+            Some(ref link) => Ok(link.as_bytes().to_vec()),
+            None => Err(Errno(EINVAL).into()),
+        }
+    }
+
+    pub fn fs_open(&mut self, caller: &Caller, ino: u64, flags: u32) -> Result<u64, Error> {
+        let flags = flags as c_int;
+        let mut mask = match flags & O_ACCMODE {
+            O_WRONLY => W_OK,
+            O_RDWR => R_OK | W_OK,
+            _ => R_OK,
+        };
+        if flags & O_TRUNC != 0 {
+            mask |= W_OK;
+        }
+        self.check_access(caller, ino, mask)?;
+        if flags & O_TRUNC != 0 {
+            self.fs_truncate(ino, 0)?;
+        }
+        Ok(self.open_handle(ino, flags as u32))
+    }
+
+    /// A handle for `ino` without any checks, as after `fs_create`.
+    pub fn open_handle(&mut self, ino: u64, flags: u32) -> u64 {
+        self.handles.open(ino, flags as c_int)
+    }
+
+    /// A write that fails here is retried, and reported, by the next flush.
+    pub fn fs_release(&mut self, ino: u64, fh: u64) -> Result<(), Error> {
+        self.handles.get_mut(ino, fh)?;
+        if let Err(err) = self.write_out(fh) {
+            println!("Could not write out fh={}, retrying on flush: {}", fh, err);
         }
+        self.handles.release(ino, fh)?;
+        let unlinked = self
+            .fs
+            .get(ino)
+            .map_or(false, |node| node.entry.attr.nlink == 0);
+        if unlinked && !self.handles.is_open(ino) {
+            self.free_node(ino);
+        }
+        Ok(())
     }
+
+    /// Writes out the pending writes to `ino` and commits.
+    pub fn fs_fsync(&mut self, ino: u64, fh: u64) -> Result<(), Error> {
+        self.handles.get_mut(ino, fh)?;
+        self.flush_buffers(ino)?;
+        self.commit()
+    }
+
+    pub fn fs_read(&mut self, ino: u64, fh: u64, offset: i64, size: u32) -> Result<Vec<u8>, Error> {
+        if !self.handles.get_mut(ino, fh)?.readable() {
+            return Err(Errno(EBADF).into());
+        }
+        self.flush_buffers(ino)?;
+        let backend = &mut self.backend;
+        let node = self
+            .fs
+            .get(ino)
+            .ok_or_else(|| err_msg("Could not read file"))?;
+        let file_size = node.entry.attr.size;
+        let start = min(offset as u64, file_size);
+        let end = min(start + u64::from(size), file_size);
+        let mut data = match node.entry.data {
+            Some(ref locs) => self.blocks.read(backend, locs, start, end - start)?,
+            None => Vec::new(),
+        };
+        // Anything past the last extent is a hole and reads back as zeros
+        data.resize((end - start) as usize, 0);
+        Ok(data)
+    }
+
+    /// Buffers the write but grows the file right away.
     pub fn fs_write(
         &mut self,
         ino: u64,
-        _fh: u64,
-        _offset: i64, // TODO: Investigate how this is used
+        fh: u64,
+        offset: i64,
         data: &[u8],
         _flags: u32,
     ) -> Result<u32, Error> {
+        let file_size = self
+            .fs
+            .get(ino)
+            .ok_or_else(|| err_msg("Could not find inode"))?
+            .entry
+            .attr
+            .size;
+        let handle = self.handles.get_mut(ino, fh)?;
+        if !handle.writable() {
+            return Err(Errno(EBADF).into());
+        }
+        let offset = if handle.append() {
+            file_size
+        } else {
+            offset as u64
+        };
+        let end = offset + data.len() as u64;
+        for other in self.handles.conflicting(ino, fh, offset, end) {
+            self.write_out(other)?;
+        }
+        self.handles.get_mut(ino, fh)?.buffer(offset, data);
+        let node = self.fs.get_mut(ino).unwrap();
+        if end > node.entry.attr.size {
+            self.size += (end - node.entry.attr.size) as usize;
+            node.entry.attr.size = end;
+        }
+        Ok(data.len() as u32)
+    }
+
+    /// Keeps the write pending if it fails.
+    fn write_out(&mut self, fh: u64) -> Result<(), Error> {
+        let (ino, buffer) = match self.handles.take_buffer(fh) {
+            Some(pending) => pending,
+            None => return Ok(()),
+        };
+        let result = self.write_buffer(ino, &buffer);
+        match result {
+            Ok(()) => self.handles.written(fh),
+            Err(_) => self.handles.restore_buffer(fh, buffer),
+        }
+        result
+    }
+
+    fn write_buffer(&mut self, ino: u64, buffer: &WriteBuffer) -> Result<(), Error> {
+        let backend = &mut self.backend;
+        // The file may have been removed while still open
+        if let Some(node) = self.fs.get_mut(ino) {
+            let locs = node.entry.data.get_or_insert_with(Vec::new);
+            let changed = self
+                .blocks
+                .write(backend, locs, buffer.offset, &buffer.data)?;
+            self.changed_extents(ino, changed);
+        }
+        Ok(())
+    }
+
+    fn changed_extents(&mut self, ino: u64, from: Option<usize>) {
+        if let Some(from) = from {
+            let mark = self.extents.entry(ino).or_insert(from);
+            *mark = min(*mark, from);
+        }
+    }
+
+    fn flush_buffers(&mut self, ino: u64) -> Result<(), Error> {
+        for fh in self.handles.buffered(Some(ino)) {
+            self.write_out(fh)?;
+        }
+        Ok(())
+    }
+
+    pub fn fs_truncate(&mut self, ino: u64, size: u64) -> Result<(), Error> {
+        self.flush_buffers(ino)?;
         let node = self
             .fs
             .get_mut(ino)
             .ok_or_else(|| err_msg("Could not find inode"))?;
-        let add_size = data.len();
-        node.entry.data = Some(self.blocks.alloc(data.to_vec()));
-        node.entry.attr.size += add_size as u64;
-        self.size += add_size;
-        Ok(add_size as u32)
-    }
-
-    pub fn fs_delete(&mut self, parent: u64, name: &OsStr) -> Result<(), Error> {
-        let name = name.to_str().expect("Could not parse os str").to_string();
-        match self.inodes.get(&name) {
-            Some(&idx) => {
-                self.fs.delete(Some(parent), idx);
+        let entry = &mut node.entry;
+        if entry.attr.kind.is_dir() {
+            return Err(Errno(EISDIR).into());
+        }
+        let changed = match entry.data {
+            Some(ref mut locs) => self.blocks.truncate(locs, size),
+            None => None,
+        };
+        // Growing leaves the new tail unallocated; reads fill it with zeros
+        self.size -= entry.attr.size as usize;
+        self.size += size as usize;
+        entry.attr.size = size;
+        let ts = time::now().to_timespec();
+        entry.attr.mtime = EncodeTimespec::marshal(ts);
+        entry.attr.ctime = EncodeTimespec::marshal(ts);
+        self.changed_extents(ino, changed);
+        Ok(())
+    }
+
+    pub fn fs_rename(
+        &mut self,
+        caller: &Caller,
+        parent: u64,
+        name: &OsStr,
+        newparent: u64,
+        newname: &OsStr,
+    ) -> Result<(), Error> {
+        let name = &self.encode_name(name)?;
+        let newname = &self.encode_name(newname)?;
+        let ino = self.fs.lookup(parent, name).ok_or(Errno(ENOENT))?;
+        if self.fs.get(newparent).is_none() {
+            return Err(Errno(ENOENT).into());
+        }
+
+        // A directory can't be moved underneath itself
+        let mut ancestor = Some(newparent);
+        while let Some(idx) = ancestor {
+            if idx == ino {
+                return Err(Errno(EINVAL).into());
+            }
+            ancestor = self.fs.get(idx).and_then(|node| node.parent);
+        }
+
+        let is_dir = self.fs.get(ino).unwrap().entry.attr.kind.is_dir();
+        self.check_delete(caller, parent, ino)?;
+        self.check_access(caller, newparent, W_OK | X_OK)?;
+        // Moving a directory rewrites its `..`
+        if is_dir && parent != newparent {
+            self.check_access(caller, ino, W_OK)?;
+        }
+        if let Some(target) = self.fs.lookup(newparent, newname) {
+            // Two links to the same inode: nothing to do
+            if target == ino {
+                return Ok(());
+            }
+            self.check_delete(caller, newparent, target)?;
+            let target_node = self.fs.get(target).unwrap();
+            match (is_dir, target_node.entry.attr.kind.is_dir()) {
+                (false, true) => return Err(Errno(EISDIR).into()),
+                (true, false) => return Err(Errno(ENOTDIR).into()),
+                (true, true) if !target_node.children.is_empty() => {
+                    return Err(Errno(ENOTEMPTY).into())
+                }
+                _ => {}
+            }
+        }
+
+        if let Some(replaced) = self.fs.rename(parent, name, newparent, newname)? {
+            self.drop_link(replaced);
+        }
+        if is_dir && parent != newparent {
+            self.fs.get_mut(parent).unwrap().entry.attr.nlink -= 1;
+            self.fs.get_mut(newparent).unwrap().entry.attr.nlink += 1;
+        }
+        let node = self.fs.get_mut(ino).unwrap();
+        node.entry.attr.ctime = EncodeTimespec::marshal(time::now().to_timespec());
+        Ok(())
+    }
+
+    pub fn fs_link(
+        &mut self,
+        caller: &Caller,
+        ino: u64,
+        newparent: u64,
+        newname: &OsStr,
+    ) -> Result<FileAttr, Error> {
+        let newname = &self.encode_name(newname)?;
+        if self.fs.get(newparent).is_none() {
+            return Err(Errno(ENOENT).into());
+        }
+        if self.fs.lookup(newparent, newname).is_some() {
+            return Err(Errno(EEXIST).into());
+        }
+        self.check_access(caller, newparent, W_OK | X_OK)?;
+        let node = self.fs.get_mut(ino).ok_or(Errno(ENOENT))?;
+        if node.entry.attr.kind.is_dir() {
+            return Err(Errno(EPERM).into());
+        }
+        // Unlinked but still open
+        if node.entry.attr.nlink == 0 {
+            return Err(Errno(ENOENT).into());
+        }
+        node.entry.attr.nlink += 1;
+        node.entry.attr.ctime = EncodeTimespec::marshal(time::now().to_timespec());
+        let attr = node.entry.attr.unmarshal();
+        self.fs.link(newparent, newname, ino)?;
+        Ok(attr)
+    }
+
+    /// `rmdir` if `dir`, `unlink` otherwise.
+    pub fn fs_delete(
+        &mut self,
+        caller: &Caller,
+        parent: u64,
+        name: &OsStr,
+        dir: bool,
+    ) -> Result<(), Error> {
+        let name = &self.encode_name(name)?;
+        let ino = self.fs.lookup(parent, name).ok_or(Errno(ENOENT))?;
+        self.check_delete(caller, parent, ino)?;
+        match (dir, self.fs.get(ino).unwrap().entry.attr.kind.is_dir()) {
+            (true, false) => return Err(Errno(ENOTDIR).into()),
+            (false, true) => return Err(Errno(EISDIR).into()),
+            _ => {}
+        }
+        if !self.fs.get(ino).unwrap().children.is_empty() {
+            return Err(Errno(ENOTEMPTY).into());
+        }
+        self.fs.unlink(parent, name);
+        self.drop_link(ino);
+        Ok(())
+    }
+
+    /// Drops a link, freeing `ino` once the last one is gone.
+    fn drop_link(&mut self, ino: u64) {
+        let unreferenced = match self.fs.get_mut(ino) {
+            Some(node) => {
+                let attr = &mut node.entry.attr;
+                attr.nlink = if attr.kind.is_dir() {
+                    0
+                } else {
+                    attr.nlink.saturating_sub(1)
+                };
+                attr.ctime = EncodeTimespec::marshal(time::now().to_timespec());
+                attr.nlink == 0
+            }
+            None => false,
+        };
+        // A file still open stays readable until its last handle is released
+        if unreferenced && !self.handles.is_open(ino) {
+            self.free_node(ino);
+        }
+    }
+
+    pub fn fs_setxattr(
+        &mut self,
+        caller: &Caller,
+        ino: u64,
+        name: &OsStr,
+        value: &[u8],
+        flags: u32,
+    ) -> Result<(), Error> {
+        let name = name.to_str().ok_or(Errno(EINVAL))?;
+        self.check_access(caller, ino, W_OK)?;
+        let entry = &mut self.fs.get_mut(ino).ok_or(Errno(ENOENT))?.entry;
+        let exists = entry.xattrs.contains_key(name);
+        if flags & XATTR_CREATE as u32 != 0 && exists {
+            return Err(Errno(EEXIST).into());
+        }
+        if flags & XATTR_REPLACE as u32 != 0 && !exists {
+            return Err(Errno(ENOATTR).into());
+        }
+        entry.xattrs.insert(name.to_owned(), value.to_vec());
+        entry.attr.ctime = EncodeTimespec::marshal(time::now().to_timespec());
+        Ok(())
+    }
+
+    pub fn fs_getxattr(&self, caller: &Caller, ino: u64, name: &OsStr) -> Result<Vec<u8>, Error> {
+        let name = name.to_str().ok_or(Errno(ENOATTR))?;
+        self.check_access(caller, ino, R_OK)?;
+        let entry = &self.fs.get(ino).ok_or(Errno(ENOENT))?.entry;
+        match entry.xattrs.get(name) {
+            Some(value) => Ok(value.clone()),
+            None => Err(Errno(ENOATTR).into()),
+        }
+    }
+
+    /// Attribute names as the kernel expects them: each one NUL terminated.
+    pub fn fs_listxattr(&self, caller: &Caller, ino: u64) -> Result<Vec<u8>, Error> {
+        self.check_access(caller, ino, R_OK)?;
+        let entry = &self.fs.get(ino).ok_or(Errno(ENOENT))?.entry;
+        let mut names = Vec::new();
+        for name in entry.xattrs.keys() {
+            names.extend_from_slice(name.as_bytes());
+            names.push(0);
+        }
+        Ok(names)
+    }
+
+    pub fn fs_removexattr(&mut self, caller: &Caller, ino: u64, name: &OsStr) -> Result<(), Error> {
+        let name = name.to_str().ok_or(Errno(ENOATTR))?;
+        self.check_access(caller, ino, W_OK)?;
+        let entry = &mut self.fs.get_mut(ino).ok_or(Errno(ENOENT))?.entry;
+        match entry.xattrs.remove(name) {
+            Some(_) => {
+                entry.attr.ctime = EncodeTimespec::marshal(time::now().to_timespec());
                 Ok(())
             }
-            None => Err(err_msg(format!("Could not find node with name {}", name))),
+            None => Err(Errno(ENOATTR).into()),
+        }
+    }
+
+    /// Every free block could still hold one more inode.
+    pub fn fs_statfs(&self) -> Statfs {
+        let used = (self.blocks.used() + STATFS_BLOCK_SIZE - 1) / STATFS_BLOCK_SIZE;
+        let blocks = self.config.quota / STATFS_BLOCK_SIZE;
+        let free = blocks.saturating_sub(used);
+        Statfs {
+            blocks,
+            free,
+            files: self.fs.count() + free,
+            ffree: free,
         }
     }
 
-    pub fn serialize(&self) -> String {
-        serde_json::to_string(self).expect("Could not serialize fs to json")
+    /// The snapshot as a superblock, padded when names are encrypted.
+    pub fn serialize(&self) -> Result<Vec<u8>, Error> {
+        self.encode(Kind::Checkpoint, self)
     }
 
+    /// The changes since the last superblock as a journal entry.
+    fn serialize_journal(&self) -> Result<Vec<u8>, Error> {
+        let (changes, entries) = self.fs.changes(FileSystemEntry::without_data);
+        let extents = self
+            .extents
+            .iter()
+            .filter_map(|(ino, from)| {
+                let locs = self.fs.get(*ino)?.entry.data.as_ref()?;
+                Some(ExtentDelta {
+                    ino: *ino,
+                    from: *from,
+                    locs: locs.get(*from..).unwrap_or_default().to_vec(),
+                })
+            })
+            .collect();
+        let entry = JournalEntry {
+            schema: SCHEMA_VERSION,
+            changes,
+            entries,
+            extents,
+            pool: self.blocks.delta(&self.synced, self.chunk_mark),
+            inode: self.inode,
+            size: self.size,
+        };
+        self.encode(Kind::Journal, &entry)
+    }
+
+    fn encode<T: ::serde::Serialize>(&self, kind: Kind, value: &T) -> Result<Vec<u8>, Error> {
+        let encoded = serde_cbor::to_vec(value)?;
+        let payload = zstd::encode_all(&encoded[..], ZSTD_COMPRESSION_LEVEL)?;
+        Ok(superblock::encode(
+            kind,
+            self.seq,
+            &payload,
+            self.encrypted_names,
+        ))
+    }
+
+    /// Compacts at most every `COMPACTION_INTERVAL` superblocks.
     pub fn fs_flush(&mut self) -> Result<(), Error> {
-        self.blocks.sync()?;
-        let serialized = self.serialize();
-        SESSION
-            .lock()
-            .expect("Could not acquire Session lock")
-            .message(serialized, None)?;
+        let threshold = match self.compacted {
+            Some(seq) if self.seq - seq < COMPACTION_INTERVAL => None,
+            _ => Some(self.config.compaction_threshold),
+        };
+        self.flush_compacting(threshold)
+    }
+
+    /// Flushes after compacting every block that isn't full.
+    pub fn fs_compact(&mut self) -> Result<(), Error> {
+        self.flush_compacting(Some(1.0))
+    }
+
+    fn flush_compacting(&mut self, threshold: Option<f64>) -> Result<(), Error> {
+        for fh in self.handles.buffered(None) {
+            self.write_out(fh)?;
+        }
+        let compaction = match threshold {
+            Some(threshold) => self.compact(threshold)?,
+            None => None,
+        };
+        // Moved extents are too many to journal
+        self.checkpoint_due |= compaction.is_some();
+        let result = self.commit();
+        match (&result, compaction) {
+            (Ok(_), Some(_)) => self.compacted = Some(self.seq),
+            // The old blocks are only let go once the new metadata is posted
+            (Err(_), Some(compaction)) => self.undo_compaction(compaction),
+            _ => {}
+        }
+        result
+    }
+
+    /// Posts a journal entry, or a checkpoint when one is due.
+    fn commit(&mut self) -> Result<(), Error> {
+        let synced = self.blocks.sync(&mut self.backend)?;
+        self.synced.extend(synced);
+        let checkpoint =
+            self.checkpoint_due || self.seq + 1 - self.checkpoint >= CHECKPOINT_INTERVAL;
+        if !checkpoint
+            && self.synced.is_empty()
+            && !self.fs.has_changes()
+            && self.extents.is_empty()
+        {
+            return Ok(());
+        }
+        self.seq += 1;
+        let serialized = if checkpoint {
+            self.serialize()
+        } else {
+            self.serialize_journal()
+        };
+        if let Err(err) = serialized.and_then(|serialized| self.post(&serialized)) {
+            self.seq -= 1;
+            return Err(err);
+        }
+        if checkpoint {
+            self.checkpoint = self.seq;
+            self.checkpoint_due = false;
+        }
+        self.fs.clear_changes();
+        self.extents.clear();
+        self.synced.clear();
+        self.chunk_mark = self.blocks.last_chunk_id();
         Ok(())
     }
 
-    pub fn find(&mut self, inode: u64) -> Option<&mut Node<FileSystemEntry>> {
-        self.fs.get_mut(inode)
+    fn post(&mut self, serialized: &[u8]) -> Result<(), Error> {
+        let backend = &mut self.backend;
+        if serialized.len() as u64 <= MAX_INLINE_METADATA {
+            return backend.post_metadata(serialized);
+        }
+        // Too long for a message: upload it like a block and post where it is
+        let parts = serialized
+            .chunks(METADATA_PART_SIZE as usize)
+            .map(|part| backend.put_blob(part))
+            .collect::<Result<Vec<_>, _>>()?;
+        let pointer = Pointer {
+            seq: self.seq,
+            parts,
+        };
+        backend.post_metadata(&superblock::encode_pointer(&pointer)?)
+    }
+
+    /// Rewrites blocks less than `threshold` full into fresh ones.
+    fn compact(&mut self, threshold: f64) -> Result<Option<Compaction>, Error> {
+        let ids = self.blocks.compactable(threshold);
+        if ids.is_empty() {
+            return Ok(None);
+        }
+        let backend = &mut self.backend;
+        let mut retired = self.blocks.retire(&ids);
+        let chunks = match self.blocks.relocate_chunks(backend, &mut retired) {
+            Ok(chunks) => chunks,
+            Err(err) => {
+                self.blocks.unretire(retired);
+                return Err(err);
+            }
+        };
+        let mut compaction = Compaction {
+            moved: Vec::new(),
+            chunks,
+            retired,
+        };
+        let mut failed = None;
+        for node in self.fs.nodes_mut() {
+            let ino = node.entry.attr.ino;
+            let locs = match node.entry.data {
+                Some(ref mut locs) => locs,
+                None => continue,
+            };
+            if !locs
+                .iter()
+                .any(|loc| compaction.retired.contains_key(&loc.block_id))
+            {
+                continue;
+            }
+            match self.blocks.relocate(backend, &mut compaction.retired, locs) {
+                Ok(relocated) => compaction.moved.push((ino, mem::replace(locs, relocated))),
+                Err(err) => {
+                    failed = Some(err);
+                    break;
+                }
+            }
+        }
+        match failed {
+            Some(err) => {
+                self.undo_compaction(compaction);
+                Err(err)
+            }
+            None => Ok(Some(compaction)),
+        }
+    }
+
+    /// Puts the moved files and retired blocks back as they were.
+    fn undo_compaction(&mut self, compaction: Compaction) {
+        for (ino, locs) in compaction.moved {
+            if let Some(node) = self.fs.get_mut(ino) {
+                node.entry.data = Some(locs);
+            }
+        }
+        self.blocks.unrelocate_chunks(compaction.chunks);
+        self.blocks.unretire(compaction.retired);
+        self.recount_blocks();
+    }
+}
+
+impl<S: StorageBackend> MessengerFS<CryptoBackend<S>> {
+    /// Like `new`, with names encrypted if configured.
+    pub fn new_encrypted(backend: CryptoBackend<S>, config: FsConfig) -> Result<Self, Error> {
+        let mut fs = Self::open(backend, config)?;
+        fs.apply_name_setting()?;
+        Ok(fs)
+    }
+
+    /// Encrypts or decrypts every name if `encrypt_names` changed.
+    fn apply_name_setting(&mut self) -> Result<(), Error> {
+        let cipher = match self.backend.name_key()? {
+            Some(key) => NameCipher::new(&key),
+            None if self.config.encrypt_names || self.encrypted_names => {
+                return Err(KeyError("Encrypted names need a passphrase").into())
+            }
+            None => return Ok(()),
+        };
+        match (self.encrypted_names, self.config.encrypt_names) {
+            (false, true) => {
+                self.fs
+                    .rename_all(|name| Ok::<_, Error>(cipher.encrypt(name)))?;
+                self.checkpoint_due = true;
+            }
+            (true, false) => {
+                self.fs.rename_all(|name| cipher.decrypt(name))?;
+                self.checkpoint_due = true;
+            }
+            _ => {}
+        }
+        self.encrypted_names = self.config.encrypt_names;
+        if self.encrypted_names {
+            self.names = Some(cipher);
+        }
+        Ok(())
+    }
+
+    /// Re-encrypts every name under the current name key.
+    fn rotate_names(&mut self) -> Result<(), Error> {
+        let old = match self.names.take() {
+            Some(old) => old,
+            None => return Ok(()),
+        };
+        let key = match self.backend.name_key()? {
+            Some(key) => key,
+            None => {
+                self.names = Some(old);
+                return Err(KeyError("Encrypted names need a passphrase").into());
+            }
+        };
+        let new = NameCipher::new(&key);
+        let result = self
+            .fs
+            .rename_all(|name| Ok(new.encrypt(&old.decrypt(name)?)));
+        self.names = Some(if result.is_ok() { new } else { old });
+        result
+    }
+
+    /// Seals every block under a new key, resuming an interrupted run.
+    pub fn fs_rekey(&mut self) -> Result<(), Error> {
+        self.fs_flush()?;
+        if self.stale_blocks()?.is_empty() {
+            self.backend.rotate()?;
+            self.rotate_names()?;
+        }
+        // Older superblocks may be sealed under keys about to be retired
+        self.checkpoint_due = true;
+        loop {
+            let stale = self.stale_blocks()?;
+            if stale.is_empty() {
+                break;
+            }
+            let backend = &mut self.backend;
+            for id in stale.iter().take(REKEY_BATCH_SIZE) {
+                self.blocks.reupload(backend, *id)?;
+                self.synced.push(*id);
+            }
+            self.commit()?;
+            println!(
+                "Re-encrypted {} blocks, {} left",
+                min(stale.len(), REKEY_BATCH_SIZE),
+                stale.len().saturating_sub(REKEY_BATCH_SIZE)
+            );
+        }
+        self.backend.retire_keys();
+        self.commit()
+    }
+
+    /// Wraps the volume keys under a new passphrase without touching blocks.
+    pub fn fs_passwd(&mut self, passphrase: String) -> Result<(), Error> {
+        self.backend.rewrap(passphrase)?;
+        // Older superblocks stay sealed under the old passphrase
+        self.checkpoint_due = true;
+        self.fs_flush()
+    }
+
+    fn stale_blocks(&mut self) -> Result<Vec<u64>, Error> {
+        let backend = &mut self.backend;
+        Ok(self
+            .blocks
+            .urls()
+            .into_iter()
+            .filter(|(_, url)| !backend.is_current(url))
+            .map(|(id, _)| id)
+            .collect())
     }
 }
@@ -0,0 +1,167 @@
+use std::collections::HashMap;
+
+use failure::{err_msg, Error};
+use serde::de::DeserializeOwned;
+use serde_json::{Map, Number, Value};
+
+/// Layout of the metadata written by this version.
+pub const SCHEMA_VERSION: u32 = 1;
+
+/// Upgrades metadata from the schema at its index to the next one.
+struct Migration {
+    snapshot: fn(&mut Value),
+    /// `None` if upgrading needs the whole tree.
+    journal: Option<fn(&mut Value)>,
+}
+
+const MIGRATIONS: &[Migration] = &[
+    // 0 -> 1
+    Migration {
+        snapshot: migrate_baseline,
+        journal: None,
+    },
+];
+
+/// Just the schema of a payload.
+#[derive(Deserialize)]
+struct Versioned {
+    schema: u32,
+}
+
+/// Decodes a JSON snapshot from before superblocks.
+pub fn decode_legacy<T: DeserializeOwned>(metadata: &[u8]) -> Result<T, Error> {
+    let mut metadata: Value = serde_json::from_slice(metadata)?;
+    upgrade(&mut metadata, 0, |migration| Some(migration.snapshot))?;
+    Ok(serde_json::from_value(metadata)?)
+}
+
+/// Also returns whether the checkpoint was upgraded.
+pub fn decode_snapshot<T: DeserializeOwned>(payload: &[u8]) -> Result<(T, bool), Error> {
+    decode(payload, |migration| Some(migration.snapshot))
+}
+
+pub fn decode_journal<T: DeserializeOwned>(payload: &[u8]) -> Result<T, Error> {
+    Ok(decode(payload, |migration| migration.journal)?.0)
+}
+
+fn decode<T, F>(payload: &[u8], step: F) -> Result<(T, bool), Error>
+where
+    T: DeserializeOwned,
+    F: Fn(&Migration) -> Option<fn(&mut Value)>,
+{
+    let schema = serde_cbor::from_slice::<Versioned>(payload)?.schema;
+    if schema == SCHEMA_VERSION {
+        return Ok((serde_cbor::from_slice(payload)?, false));
+    }
+    // Migrations are written against JSON, like the snapshots they started with
+    let mut metadata = to_json(serde_cbor::from_slice(payload)?);
+    upgrade(&mut metadata, schema, step)?;
+    Ok((serde_json::from_value(metadata)?, true))
+}
+
+fn upgrade<F>(metadata: &mut Value, schema: u32, step: F) -> Result<(), Error>
+where
+    F: Fn(&Migration) -> Option<fn(&mut Value)>,
+{
+    if schema > SCHEMA_VERSION {
+        return Err(err_msg(format!(
+            "Metadata schema {} needs a newer messenger-fs",
+            schema
+        )));
+    }
+    for (from, migration) in MIGRATIONS.iter().enumerate().skip(schema as usize) {
+        let migrate = step(migration).ok_or_else(|| {
+            err_msg(format!(
+                "Metadata from schema {} can only be upgraded in a full snapshot",
+                from
+            ))
+        })?;
+        println!("Upgrading metadata from schema {} to {}", from, from + 1);
+        migrate(metadata);
+    }
+    if let Some(fields) = metadata.as_object_mut() {
+        fields.insert("schema".to_owned(), json!(SCHEMA_VERSION));
+    }
+    Ok(())
+}
+
+/// The same structure as JSON, with map keys as strings.
+fn to_json(value: serde_cbor::Value) -> Value {
+    use serde_cbor::Value as Cbor;
+    match value {
+        Cbor::Null => Value::Null,
+        Cbor::Bool(b) => Value::Bool(b),
+        Cbor::Integer(i) if i < 0 => json!(i as i64),
+        Cbor::Integer(i) => json!(i as u64),
+        Cbor::Float(f) => Number::from_f64(f).map_or(Value::Null, Value::Number),
+        Cbor::Bytes(bytes) => json!(bytes),
+        Cbor::Text(text) => Value::String(text),
+        Cbor::Array(values) => Value::Array(values.into_iter().map(to_json).collect()),
+        Cbor::Map(entries) => Value::Object(
+            entries
+                .into_iter()
+                .map(|(key, value)| {
+                    let key = match to_json(key) {
+                        Value::String(key) => key,
+                        key => key.to_string(),
+                    };
+                    (key, to_json(value))
+                })
+                .collect::<Map<_, _>>(),
+        ),
+        Cbor::Tag(_, value) => to_json(*value),
+        _ => Value::Null,
+    }
+}
+
+/// Rebuilds the directory maps and link counts from the global name map.
+fn migrate_baseline(metadata: &mut Value) {
+    if let Some(fields) = metadata.as_object_mut() {
+        fields.remove("inodes");
+    }
+    let arena = match metadata["fs"]["arena"].as_object_mut() {
+        Some(arena) => arena,
+        None => return,
+    };
+    let names = arena
+        .iter()
+        .filter_map(|(idx, node)| Some((idx.clone(), node["entry"]["name"].as_str()?.to_owned())))
+        .collect::<HashMap<_, _>>();
+    for node in arena.values_mut() {
+        let children = match node["children"].as_array() {
+            Some(children) => children
+                .iter()
+                .filter_map(|child| {
+                    let name = names.get(&child.as_u64()?.to_string())?;
+                    Some((name.clone(), child.clone()))
+                })
+                .collect(),
+            None => continue,
+        };
+        node["children"] = Value::Object(children);
+    }
+    let is_dir = |node: &Value| node["entry"]["attr"]["kind"] == "Directory";
+    let mut links = HashMap::new();
+    for (idx, node) in arena.iter() {
+        let children = node["children"]
+            .as_object()
+            .into_iter()
+            .flat_map(|c| c.values());
+        for child in children {
+            let child = child.to_string();
+            *links.entry(child.clone()).or_insert(0) += 1;
+            // A subdirectory's `..` links back to its parent
+            if arena.get(&child).map_or(false, &is_dir) {
+                *links.entry(idx.clone()).or_insert(0) += 1;
+            }
+        }
+    }
+    for (idx, node) in arena.iter_mut() {
+        let mut nlink = links.get(idx).cloned().unwrap_or(0);
+        // Plus the directory's own `.` entry, and the root's missing parent
+        if is_dir(node) {
+            nlink += if node["parent"].is_null() { 2 } else { 1 };
+        }
+        node["entry"]["attr"]["nlink"] = json!(nlink);
+    }
+}
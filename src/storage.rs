@@ -0,0 +1,32 @@
+use failure::Error;
+
+/// A message that may hold a metadata snapshot.
+pub struct Candidate {
+    /// Where to page on from to older candidates.
+    pub cursor: String,
+    /// The snapshot, or why it couldn't be read.
+    pub metadata: Result<Vec<u8>, Error>,
+}
+
+/// Where the filesystem persists to, e.g. `Session`.
+pub trait StorageBackend {
+    /// Uploads a data blob and returns the url it can be fetched back from.
+    fn put_blob(&mut self, data: &[u8]) -> Result<String, Error>;
+
+    /// Downloads the blob stored at `url`.
+    fn get_blob(&mut self, url: &str) -> Result<Vec<u8>, Error>;
+
+    /// Posts a serialized metadata snapshot.
+    fn post_metadata(&mut self, metadata: &[u8]) -> Result<(), Error>;
+
+    /// The newest message before cursor `before` that may be a snapshot.
+    fn fetch_metadata(&mut self, before: Option<&str>) -> Result<Option<Candidate>, Error>;
+
+    /// Told which candidate the filesystem was restored from.
+    fn accept_metadata(&mut self, _cursor: &str) -> Result<(), Error> {
+        Ok(())
+    }
+
+    /// Whether any blob has been uploaded at all.
+    fn has_blobs(&mut self) -> Result<bool, Error>;
+}
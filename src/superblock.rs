@@ -0,0 +1,142 @@
+use std::cmp::max;
+use std::error;
+use std::fmt;
+
+use failure::{err_msg, Error};
+
+use common::constants::METADATA_PADDING_MIN;
+
+/// Starts a superblock holding a full snapshot.
+const CHECKPOINT_MAGIC: &[u8; 4] = b"MFSB";
+/// Starts a superblock holding the changes since the previous one.
+const JOURNAL_MAGIC: &[u8; 4] = b"MFSJ";
+/// Starts a pointer to a superblock stored as blobs.
+const POINTER_MAGIC: &[u8; 4] = b"MFSP";
+/// Format written by this version.
+pub const VERSION: u16 = 1;
+/// Oldest reader that can decode what this version writes.
+const COMPAT_VERSION: u16 = 1;
+const HEADER_SIZE: usize = 4 + 2 + 2 + 2 + 8 + 8 + 32;
+
+/// Versions, sizes, sequence number and BLAKE3 hash after the magic.
+pub struct Header {
+    pub kind: Kind,
+    pub version: u16,
+    pub seq: u64,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Kind {
+    Checkpoint,
+    Journal,
+}
+
+/// Blocks exist but no snapshot describes them.
+#[derive(Debug)]
+pub struct MissingSuperblock;
+
+impl fmt::Display for MissingSuperblock {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Found blocks but no valid superblock")
+    }
+}
+
+impl error::Error for MissingSuperblock {}
+
+/// The blobs holding a superblock too large for a message.
+#[derive(Serialize, Deserialize)]
+pub struct Pointer {
+    pub seq: u64,
+    pub parts: Vec<String>,
+}
+
+/// A `padded` superblock is zero-filled to a power of two.
+pub fn encode(kind: Kind, seq: u64, payload: &[u8], padded: bool) -> Vec<u8> {
+    let (magic, min_size) = match kind {
+        Kind::Checkpoint => (CHECKPOINT_MAGIC, METADATA_PADDING_MIN as usize),
+        Kind::Journal => (JOURNAL_MAGIC, 0),
+    };
+    let size = if padded {
+        max(HEADER_SIZE + payload.len(), min_size).next_power_of_two()
+    } else {
+        0
+    };
+    let mut encoded = Vec::with_capacity(max(size, HEADER_SIZE + payload.len()));
+    encoded.extend_from_slice(magic);
+    encoded.extend_from_slice(&VERSION.to_le_bytes());
+    encoded.extend_from_slice(&COMPAT_VERSION.to_le_bytes());
+    encoded.extend_from_slice(&(HEADER_SIZE as u16).to_le_bytes());
+    encoded.extend_from_slice(&seq.to_le_bytes());
+    encoded.extend_from_slice(&(payload.len() as u64).to_le_bytes());
+    encoded.extend_from_slice(blake3::hash(payload).as_bytes());
+    encoded.extend_from_slice(payload);
+    if encoded.len() < size {
+        encoded.resize(size, 0);
+    }
+    encoded
+}
+
+/// Whether `data` is a superblock at all, rather than an older snapshot.
+pub fn is_superblock(data: &[u8]) -> bool {
+    data.starts_with(CHECKPOINT_MAGIC) || data.starts_with(JOURNAL_MAGIC)
+}
+
+/// Checks a superblock and returns its header and payload.
+pub fn decode(data: &[u8]) -> Result<(Header, &[u8]), Error> {
+    if !is_superblock(data) || data.len() < HEADER_SIZE {
+        return Err(err_msg("Not a superblock"));
+    }
+    let u16_at = |at: usize| u16::from_le_bytes([data[at], data[at + 1]]);
+    let u64_at = |at: usize| {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(&data[at..at + 8]);
+        u64::from_le_bytes(bytes)
+    };
+    let kind = if data.starts_with(JOURNAL_MAGIC) {
+        Kind::Journal
+    } else {
+        Kind::Checkpoint
+    };
+    let version = u16_at(4);
+    let compat = u16_at(6);
+    if compat > VERSION {
+        return Err(err_msg(format!(
+            "Superblock version {} needs a newer messenger-fs",
+            version
+        )));
+    }
+    let header_size = u16_at(8) as usize;
+    let seq = u64_at(10);
+    let size = u64_at(18) as usize;
+    let checksum = &data[26..58];
+    if header_size < HEADER_SIZE || data.len() < header_size + size {
+        return Err(err_msg("Superblock is truncated"));
+    }
+    let payload = &data[header_size..header_size + size];
+    if blake3::hash(payload).as_bytes() != checksum {
+        return Err(err_msg("Superblock checksum mismatch"));
+    }
+    Ok((Header { kind, version, seq }, payload))
+}
+
+pub fn encode_pointer(pointer: &Pointer) -> Result<Vec<u8>, Error> {
+    let mut encoded = POINTER_MAGIC.to_vec();
+    encoded.extend(serde_cbor::to_vec(pointer)?);
+    Ok(encoded)
+}
+
+pub fn is_pointer(data: &[u8]) -> bool {
+    data.starts_with(POINTER_MAGIC)
+}
+
+/// Whether `data` is a snapshot of any format rather than a chat message.
+pub fn is_snapshot(data: &[u8]) -> bool {
+    is_superblock(data) || is_pointer(data) || data.starts_with(b"{")
+}
+
+pub fn decode_pointer(data: &[u8]) -> Result<Pointer, Error> {
+    if !is_pointer(data) {
+        return Err(err_msg("Not a superblock pointer"));
+    }
+    Ok(serde_cbor::from_slice(&data[POINTER_MAGIC.len()..])?)
+}
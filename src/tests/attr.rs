@@ -0,0 +1,75 @@
+use std::ffi::OsStr;
+
+use fuse::FileType;
+use libc::EPERM;
+
+use config::FsConfig;
+use messengerfs::SetAttr;
+use tests::{create, errno, Harness, ROOT, USER};
+
+#[test]
+fn creates_with_the_requested_mode() {
+    let harness = Harness::new();
+    let config = FsConfig {
+        umask: 0o022,
+        ..FsConfig::default()
+    };
+    let mut fs = harness.mount_with(config).unwrap();
+    let file = FileType::RegularFile;
+    let attr = fs
+        .fs_create(&ROOT, 1, OsStr::new("script"), file, 0o4777, 0)
+        .unwrap();
+    assert_eq!(attr.perm, 0o4755);
+    let attr = fs
+        .fs_create(&ROOT, 1, OsStr::new("dir"), FileType::Directory, 0o700, 0)
+        .unwrap();
+    assert_eq!(attr.perm, 0o700);
+}
+
+#[test]
+fn chmod_and_chown_through_setattr() {
+    let harness = Harness::new();
+    let mut fs = harness.mount();
+    let file = create(&mut fs, 1, "notes");
+    let chmod = SetAttr {
+        mode: Some(0o100640),
+        ..SetAttr::default()
+    };
+    assert_eq!(fs.fs_setattr(&ROOT, file, &chmod).unwrap().perm, 0o640);
+    let chown = SetAttr {
+        uid: Some(USER.uid),
+        gid: Some(USER.gid),
+        ..SetAttr::default()
+    };
+    let attr = fs.fs_setattr(&ROOT, file, &chown).unwrap();
+    assert_eq!((attr.uid, attr.gid), (USER.uid, USER.gid));
+    assert_eq!(attr.perm, 0o640);
+    fs.fs_flush().unwrap();
+
+    let mut fs = harness.mount();
+    let attr = fs.fs.get(file).unwrap().entry.attr.unmarshal();
+    assert_eq!((attr.uid, attr.gid, attr.perm), (USER.uid, USER.gid, 0o640));
+    let chmod = SetAttr {
+        mode: Some(0o600),
+        ..SetAttr::default()
+    };
+    assert_eq!(fs.fs_setattr(&USER, file, &chmod).unwrap().perm, 0o600);
+}
+
+#[test]
+fn only_root_gives_a_file_away() {
+    let mut fs = Harness::new().mount();
+    let file = create(&mut fs, 1, "notes");
+    let chmod = SetAttr {
+        mode: Some(0o777),
+        ..SetAttr::default()
+    };
+    assert_eq!(errno(fs.fs_setattr(&USER, file, &chmod)), EPERM);
+    let chown = SetAttr {
+        uid: Some(USER.uid),
+        ..SetAttr::default()
+    };
+    assert_eq!(errno(fs.fs_setattr(&USER, file, &chown)), EPERM);
+    assert_eq!(fs.fs.get(file).unwrap().entry.attr.perm, 0o755);
+    assert_eq!(fs.fs.get(file).unwrap().entry.attr.uid, ROOT.uid);
+}
@@ -0,0 +1,75 @@
+use common::constants::KILOBYTES;
+use config::FsConfig;
+use messenger::session::Session;
+use messengerfs::MessengerFS;
+use tests::{create, read, write, Harness};
+
+const SIZE: u64 = 500 * KILOBYTES;
+
+fn data(seed: u8) -> Vec<u8> {
+    (0..SIZE)
+        .map(|i| (i as u8).wrapping_mul(31) ^ seed)
+        .collect()
+}
+
+/// Leaves a dead range in front of the file it returns.
+fn sparse(fs: &mut MessengerFS<Session>, seed: u8) -> u64 {
+    let first = create(fs, 1, &format!("first{}", seed));
+    write(fs, first, 0, &data(seed));
+    let second = create(fs, 1, &format!("second{}", seed));
+    write(fs, second, 0, &data(seed + 1));
+    fs.fs_truncate(first, 0).unwrap();
+    second
+}
+
+#[test]
+fn compacts_on_flush_at_most_every_so_often() {
+    let harness = Harness::new();
+    let mut fs = harness.mount();
+    let second = sparse(&mut fs, 0);
+    assert_eq!(fs.blocks.used(), 2 * SIZE);
+    fs.fs_flush().unwrap();
+    assert_eq!(fs.blocks.used(), SIZE);
+
+    let fourth = sparse(&mut fs, 2);
+    fs.fs_flush().unwrap();
+    assert_eq!(fs.blocks.used(), 3 * SIZE);
+    fs.fs_compact().unwrap();
+    assert_eq!(fs.blocks.used(), 2 * SIZE);
+
+    let mut fs = harness.mount();
+    assert!(read(&mut fs, second, 0, SIZE as u32) == data(1));
+    assert!(read(&mut fs, fourth, 0, SIZE as u32) == data(3));
+}
+
+#[test]
+fn leaves_blocks_fuller_than_the_threshold_to_fs_compact() {
+    let harness = Harness::new();
+    let config = FsConfig {
+        compaction_threshold: 0.05,
+        ..FsConfig::default()
+    };
+    let mut fs = harness.mount_with(config).unwrap();
+    let second = sparse(&mut fs, 0);
+    fs.fs_flush().unwrap();
+    assert_eq!(fs.blocks.used(), 2 * SIZE);
+    fs.fs_compact().unwrap();
+    assert_eq!(fs.blocks.used(), SIZE);
+
+    let mut fs = harness.mount();
+    assert_eq!(fs.blocks.used(), SIZE);
+    assert!(read(&mut fs, second, 0, SIZE as u32) == data(1));
+}
+
+#[test]
+fn keeps_a_lone_block_without_dead_bytes() {
+    let harness = Harness::new();
+    let mut fs = harness.mount();
+    let file = create(&mut fs, 1, "file");
+    write(&mut fs, file, 0, &data(0));
+    fs.fs_flush().unwrap();
+    let urls = fs.blocks.urls();
+    fs.fs_compact().unwrap();
+    assert_eq!(fs.blocks.urls(), urls);
+    assert_eq!(fs.blocks.used(), SIZE);
+}
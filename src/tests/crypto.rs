@@ -0,0 +1,85 @@
+use crypto::CryptoBackend;
+use messengerfs::MessengerFS;
+use storage::StorageBackend;
+use tests::{create, read, write, Harness};
+
+#[test]
+fn refuses_plaintext_until_encrypted() {
+    let harness = Harness::new();
+    let mut fs = harness.mount();
+    let file = create(&mut fs, 1, "diary");
+    write(&mut fs, file, 0, b"dear diary");
+    fs.fs_flush().unwrap();
+
+    assert!(harness.mount_sealed("secret").is_err());
+
+    let mut backend = harness.sealed("secret");
+    backend.accept_plaintext();
+    let mut fs = MessengerFS::new(backend, Default::default()).unwrap();
+    fs.fs_rekey().unwrap();
+
+    let mut fs = harness.mount_sealed("secret").unwrap();
+    assert_eq!(read(&mut fs, file, 0, 100), b"dear diary");
+    let backend = CryptoBackend::new(harness.session(), None);
+    assert!(MessengerFS::new(backend, Default::default()).is_err());
+}
+
+#[test]
+fn refuses_a_plaintext_block() {
+    let harness = Harness::new();
+    let url = harness.session().put_blob(b"swapped in").unwrap();
+
+    let mut backend = harness.sealed("secret");
+    assert!(backend.get_blob(&url).is_err());
+    backend.accept_plaintext();
+    assert_eq!(backend.get_blob(&url).unwrap(), b"swapped in");
+}
+
+#[test]
+fn keeps_the_keys_of_the_snapshot_it_restores() {
+    let harness = Harness::new();
+    let mut fs = harness.mount_sealed("secret").unwrap();
+    let file = create(&mut fs, 1, "diary");
+    write(&mut fs, file, 0, b"dear diary");
+    fs.fs_flush().unwrap();
+
+    // Opens with the passphrase but under other volume keys, and is no
+    // snapshot once opened
+    harness
+        .sealed("secret")
+        .post_metadata(b"MFSB not a superblock")
+        .unwrap();
+
+    let mut fs = harness.mount_sealed("secret").unwrap();
+    assert_eq!(read(&mut fs, file, 0, 100), b"dear diary");
+}
+
+#[test]
+fn restores_a_snapshot_too_long_for_a_message() {
+    let harness = Harness::new();
+    let mut fs = harness.mount_sealed("secret").unwrap();
+    // Names that don't compress, so the snapshot is uploaded in parts
+    let files = (0..1000u64)
+        .map(|i| format!("{:016x}", i.wrapping_mul(0x9e37_79b9_7f4a_7c15)))
+        .map(|name| (create(&mut fs, 1, &name), name))
+        .collect::<Vec<_>>();
+    write(&mut fs, files[0].0, 0, b"dear diary");
+    fs.fs_flush().unwrap();
+    assert!(
+        harness
+            .session()
+            .fetch_metadata(None)
+            .unwrap()
+            .unwrap()
+            .metadata
+            .unwrap()
+            .len()
+            < 1000
+    );
+
+    let mut fs = harness.mount_sealed("secret").unwrap();
+    for (file, name) in &files {
+        assert_eq!(fs.fs.lookup(1, name), Some(*file));
+    }
+    assert_eq!(read(&mut fs, files[0].0, 0, 100), b"dear diary");
+}
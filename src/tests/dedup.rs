@@ -0,0 +1,68 @@
+use common::constants::CHUNK_SIZE;
+use tests::{create, read, write, Harness};
+
+/// Two chunks that differ from each other.
+fn data() -> Vec<u8> {
+    let mut data = vec![1; CHUNK_SIZE as usize];
+    data.extend(vec![2; CHUNK_SIZE as usize]);
+    data
+}
+
+#[test]
+fn stores_identical_chunks_once() {
+    let harness = Harness::new();
+    let mut fs = harness.mount();
+    let first = create(&mut fs, 1, "first");
+    write(&mut fs, first, 0, &data());
+    assert_eq!(fs.blocks.used(), 2 * CHUNK_SIZE);
+    let second = create(&mut fs, 1, "second");
+    write(&mut fs, second, 0, &data());
+    assert_eq!(fs.blocks.used(), 2 * CHUNK_SIZE);
+    fs.fs_flush().unwrap();
+
+    let mut fs = harness.mount();
+    let third = create(&mut fs, 1, "third");
+    write(&mut fs, third, 0, &data());
+    assert_eq!(fs.blocks.used(), 2 * CHUNK_SIZE);
+    for &file in &[first, second, third] {
+        assert!(read(&mut fs, file, 0, 2 * CHUNK_SIZE as u32) == data());
+    }
+}
+
+#[test]
+fn copies_a_shared_chunk_before_writing_to_it() {
+    let mut fs = Harness::new().mount();
+    let first = create(&mut fs, 1, "first");
+    write(&mut fs, first, 0, &data());
+    let second = create(&mut fs, 1, "second");
+    write(&mut fs, second, 0, &data());
+    write(&mut fs, second, 1, b"changed");
+    assert_eq!(fs.blocks.used(), 3 * CHUNK_SIZE);
+
+    assert!(read(&mut fs, first, 0, 2 * CHUNK_SIZE as u32) == data());
+    assert_eq!(read(&mut fs, second, 0, 8), b"\x01changed");
+    let rest = read(&mut fs, second, CHUNK_SIZE, CHUNK_SIZE as u32);
+    assert!(rest[..] == data()[CHUNK_SIZE as usize..]);
+}
+
+#[test]
+fn keeps_a_chunk_while_a_file_refers_to_it() {
+    let harness = Harness::new();
+    let mut fs = harness.mount();
+    let first = create(&mut fs, 1, "first");
+    write(&mut fs, first, 0, &data());
+    let second = create(&mut fs, 1, "second");
+    write(&mut fs, second, 0, &data());
+    fs.fs_truncate(first, 0).unwrap();
+    assert_eq!(fs.blocks.used(), 2 * CHUNK_SIZE);
+    assert!(read(&mut fs, second, 0, 2 * CHUNK_SIZE as u32) == data());
+
+    fs.fs_truncate(second, 0).unwrap();
+    fs.fs_compact().unwrap();
+    assert_eq!(fs.blocks.used(), 0);
+    let mut fs = harness.mount();
+    assert_eq!(fs.blocks.used(), 0);
+    let third = create(&mut fs, 1, "third");
+    write(&mut fs, third, 0, &data());
+    assert!(read(&mut fs, third, 0, 2 * CHUNK_SIZE as u32) == data());
+}
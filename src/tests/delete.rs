@@ -0,0 +1,98 @@
+use std::ffi::OsStr;
+
+use libc::{EISDIR, ENOENT, ENOTDIR, ENOTEMPTY, O_RDWR};
+
+use tests::{create, errno, mkdir, write, Harness, ROOT};
+
+#[test]
+fn unlink_refuses_a_directory() {
+    let mut fs = Harness::new().mount();
+    let dir = mkdir(&mut fs, 1, "dir");
+
+    assert_eq!(
+        errno(fs.fs_delete(&ROOT, 1, OsStr::new("dir"), false)),
+        EISDIR
+    );
+    assert_eq!(fs.fs.lookup(1, "dir"), Some(dir));
+    assert_eq!(fs.fs.get(dir).unwrap().entry.attr.nlink, 2);
+    fs.fs_delete(&ROOT, 1, OsStr::new("dir"), true).unwrap();
+    assert!(fs.fs.lookup(1, "dir").is_none());
+}
+
+#[test]
+fn rmdir_refuses_a_file() {
+    let mut fs = Harness::new().mount();
+    let file = create(&mut fs, 1, "file");
+
+    assert_eq!(
+        errno(fs.fs_delete(&ROOT, 1, OsStr::new("file"), true)),
+        ENOTDIR
+    );
+    assert_eq!(fs.fs.lookup(1, "file"), Some(file));
+    assert_eq!(fs.fs.get(file).unwrap().entry.attr.nlink, 1);
+    fs.fs_delete(&ROOT, 1, OsStr::new("file"), false).unwrap();
+    assert!(fs.fs.lookup(1, "file").is_none());
+}
+
+#[test]
+fn rmdir_refuses_a_directory_with_entries() {
+    let mut fs = Harness::new().mount();
+    let dir = mkdir(&mut fs, 1, "dir");
+    create(&mut fs, dir, "file");
+
+    assert_eq!(
+        errno(fs.fs_delete(&ROOT, 1, OsStr::new("dir"), true)),
+        ENOTEMPTY
+    );
+    fs.fs_delete(&ROOT, dir, OsStr::new("file"), false).unwrap();
+    fs.fs_delete(&ROOT, 1, OsStr::new("dir"), true).unwrap();
+}
+
+#[test]
+fn keeps_an_unlinked_file_until_it_is_released() {
+    let mut fs = Harness::new().mount();
+    let file = create(&mut fs, 1, "file");
+    write(&mut fs, file, 0, b"still here");
+    let fh = fs.fs_open(&ROOT, file, O_RDWR as u32).unwrap();
+
+    fs.fs_delete(&ROOT, 1, OsStr::new("file"), false).unwrap();
+    assert!(fs.fs.lookup(1, "file").is_none());
+    fs.fs_write(file, fh, 10, b", and more", 0).unwrap();
+    assert_eq!(
+        fs.fs_read(file, fh, 0, 100).unwrap(),
+        b"still here, and more"
+    );
+
+    fs.fs_release(file, fh).unwrap();
+    assert!(fs.fs.get(file).is_none());
+}
+
+#[test]
+fn cannot_link_an_unlinked_file() {
+    let mut fs = Harness::new().mount();
+    let file = create(&mut fs, 1, "file");
+    let fh = fs.fs_open(&ROOT, file, O_RDWR as u32).unwrap();
+    fs.fs_delete(&ROOT, 1, OsStr::new("file"), false).unwrap();
+
+    assert_eq!(
+        errno(fs.fs_link(&ROOT, file, 1, OsStr::new("revived"))),
+        ENOENT
+    );
+    fs.fs_release(file, fh).unwrap();
+    assert!(fs.fs.get(file).is_none());
+}
+
+#[test]
+fn frees_an_unlinked_file_left_open_on_restore() {
+    let harness = Harness::new();
+    let mut fs = harness.mount();
+    let file = create(&mut fs, 1, "file");
+    write(&mut fs, file, 0, b"orphaned");
+    fs.fs_open(&ROOT, file, O_RDWR as u32).unwrap();
+    fs.fs_delete(&ROOT, 1, OsStr::new("file"), false).unwrap();
+    fs.fs_flush().unwrap();
+    assert!(fs.fs.get(file).is_some());
+
+    let fs = harness.mount();
+    assert!(fs.fs.get(file).is_none());
+}
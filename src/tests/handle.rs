@@ -0,0 +1,61 @@
+use libc::O_RDWR;
+
+use tests::{create, read, write, Harness, ROOT};
+
+#[test]
+fn last_write_wins_across_handles() {
+    let mut fs = Harness::new().mount();
+    let file = create(&mut fs, 1, "shared");
+    let first = fs.fs_open(&ROOT, file, O_RDWR as u32).unwrap();
+    let second = fs.fs_open(&ROOT, file, O_RDWR as u32).unwrap();
+
+    fs.fs_write(file, first, 0, b"aaaa", 0).unwrap();
+    fs.fs_write(file, second, 1, b"bb", 0).unwrap();
+    fs.fs_write(file, first, 2, b"c", 0).unwrap();
+    assert_eq!(read(&mut fs, file, 0, 100), b"abca");
+
+    fs.fs_write(file, second, 0, b"dd", 0).unwrap();
+    fs.fs_write(file, first, 1, b"e", 0).unwrap();
+    fs.fs_flush().unwrap();
+    assert_eq!(read(&mut fs, file, 0, 100), b"deca");
+    fs.fs_release(file, first).unwrap();
+    fs.fs_release(file, second).unwrap();
+}
+
+#[test]
+fn keeps_pending_writes_when_they_cannot_be_written() {
+    let harness = Harness::new();
+    let mut fs = harness.mount();
+    let file = create(&mut fs, 1, "notes");
+    write(&mut fs, file, 0, b"hello");
+    fs.fs_flush().unwrap();
+
+    // Restored blocks are only fetched when written to
+    let mut fs = harness.mount_flaky();
+    let fh = fs.fs_open(&ROOT, file, O_RDWR as u32).unwrap();
+    fs.fs_write(file, fh, 0, b"J", 0).unwrap();
+    fs.backend.down = true;
+    assert!(fs.fs_flush().is_err());
+    assert!(fs.fs_fsync(file, fh).is_err());
+    fs.fs_release(file, fh).unwrap();
+    assert!(fs.fs_release(file, fh).is_err());
+    assert!(fs.fs_flush().is_err());
+    fs.backend.down = false;
+    fs.fs_flush().unwrap();
+
+    let mut fs = harness.mount();
+    assert_eq!(read(&mut fs, file, 0, 100), b"Jello");
+}
+
+#[test]
+fn fsync_commits_the_handles_writes() {
+    let harness = Harness::new();
+    let mut fs = harness.mount();
+    let file = create(&mut fs, 1, "notes");
+    let fh = fs.fs_open(&ROOT, file, O_RDWR as u32).unwrap();
+    fs.fs_write(file, fh, 0, b"hello", 0).unwrap();
+    fs.fs_fsync(file, fh).unwrap();
+
+    let mut fs = harness.mount();
+    assert_eq!(read(&mut fs, file, 0, 100), b"hello");
+}
@@ -0,0 +1,92 @@
+use common::constants::{CHUNK_SIZE, REKEY_BATCH_SIZE};
+use config::FsConfig;
+use crypto::CryptoBackend;
+use messengerfs::MessengerFS;
+use storage::StorageBackend;
+use tests::{create, read, write, Flaky, Harness};
+
+#[test]
+fn changes_the_passphrase() {
+    let harness = Harness::new();
+    let mut fs = harness.mount_sealed("old").unwrap();
+    let file = create(&mut fs, 1, "diary");
+    write(&mut fs, file, 0, b"dear diary");
+    fs.fs_flush().unwrap();
+    let urls = fs.blocks.urls();
+    fs.fs_passwd("new".to_owned()).unwrap();
+    assert_eq!(fs.blocks.urls(), urls);
+    let later = create(&mut fs, 1, "later");
+    fs.fs_flush().unwrap();
+
+    // The old one only opens what was posted before
+    let fs = harness.mount_sealed("old").unwrap();
+    assert!(fs.fs.lookup(1, "later").is_none());
+    let mut fs = harness.mount_sealed("new").unwrap();
+    assert_eq!(fs.fs.lookup(1, "later"), Some(later));
+    assert_eq!(read(&mut fs, file, 0, 100), b"dear diary");
+}
+
+#[test]
+fn rekeys_every_block() {
+    let harness = Harness::new();
+    let mut fs = harness.mount_sealed("secret").unwrap();
+    let file = create(&mut fs, 1, "diary");
+    write(&mut fs, file, 0, b"dear diary");
+    fs.fs_flush().unwrap();
+    let old = fs.blocks.urls();
+    fs.fs_rekey().unwrap();
+    let new = fs.blocks.urls();
+    assert_eq!(new.len(), old.len());
+    for (old, new) in old.iter().zip(&new) {
+        assert!(old.1 != new.1);
+        assert!(fs.backend.get_blob(&old.1).is_err());
+        assert!(fs.backend.get_blob(&new.1).is_ok());
+    }
+
+    let mut fs = harness.mount_sealed("secret").unwrap();
+    assert_eq!(read(&mut fs, file, 0, 100), b"dear diary");
+}
+
+#[test]
+fn resumes_an_interrupted_rekey() {
+    let harness = Harness::new();
+    let mut fs = harness.mount_sealed("secret").unwrap();
+    // Enough blocks for more than one batch, in chunks that all differ
+    let files = (0..REKEY_BATCH_SIZE as u64 * 5 + 5)
+        .map(|i| {
+            let file = create(&mut fs, 1, &format!("file{}", i));
+            write(&mut fs, file, 0, &vec![i as u8; CHUNK_SIZE as usize]);
+            file
+        })
+        .collect::<Vec<_>>();
+    fs.fs_flush().unwrap();
+    let old = fs.blocks.urls();
+    assert!(old.len() > REKEY_BATCH_SIZE);
+
+    // Goes down after committing the first batch
+    let mut flaky = Flaky::new(harness.session());
+    flaky.posts = Some(1);
+    let backend = CryptoBackend::new(flaky, Some("secret".to_owned()));
+    let mut fs = MessengerFS::new_encrypted(backend, FsConfig::default()).unwrap();
+    assert!(fs.fs_rekey().is_err());
+
+    let mut fs = harness.mount_sealed("secret").unwrap();
+    let interrupted = fs.blocks.urls();
+    let done = interrupted
+        .iter()
+        .filter(|url| !old.contains(url))
+        .cloned()
+        .collect::<Vec<_>>();
+    assert_eq!(done.len(), REKEY_BATCH_SIZE);
+    fs.fs_rekey().unwrap();
+    let rekeyed = fs.blocks.urls();
+    for url in &done {
+        assert!(rekeyed.contains(url));
+    }
+    assert!(rekeyed.iter().all(|url| !old.contains(url)));
+
+    let mut fs = harness.mount_sealed("secret").unwrap();
+    for (i, file) in files.iter().enumerate() {
+        assert_eq!(read(&mut fs, *file, 0, 1), [i as u8]);
+    }
+}
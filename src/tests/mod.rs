@@ -0,0 +1,193 @@
+//! Runs the filesystem against the in-memory messenger server.
+
+mod attr;
+mod compact;
+mod crypto;
+mod dedup;
+mod delete;
+mod handle;
+mod keys;
+mod names;
+mod permission;
+mod rename;
+mod restore;
+mod schema;
+mod statfs;
+mod storage;
+mod superblock;
+mod symlink;
+mod truncate;
+
+use std::ffi::OsStr;
+
+use failure::{err_msg, Error};
+use fuse::FileType;
+use libc::{c_int, O_RDWR};
+
+use common::errno::Errno;
+use common::permission::Caller;
+use config::FsConfig;
+use crypto::CryptoBackend;
+use messenger::mock::MockServer;
+use messenger::session::Session;
+use messengerfs::MessengerFS;
+use storage::{Candidate, StorageBackend};
+
+pub const ROOT: Caller = Caller { uid: 0, gid: 0 };
+
+/// Anyone but root.
+pub const USER: Caller = Caller {
+    uid: 1000,
+    gid: 1000,
+};
+
+/// The errno a call failed with.
+pub fn errno<T>(result: Result<T, Error>) -> c_int {
+    match result {
+        Ok(_) => panic!("Expected the call to fail"),
+        Err(err) => err.downcast_ref::<Errno>().expect("Not an errno").0,
+    }
+}
+
+/// Passes everything through until `down` or out of metadata `posts`.
+pub struct Flaky<S> {
+    pub inner: S,
+    pub down: bool,
+    pub posts: Option<usize>,
+}
+
+impl<S> Flaky<S> {
+    pub fn new(inner: S) -> Self {
+        Self {
+            inner,
+            down: false,
+            posts: None,
+        }
+    }
+
+    fn up(&self) -> Result<(), Error> {
+        if self.down {
+            return Err(err_msg("Connection reset"));
+        }
+        Ok(())
+    }
+}
+
+impl<S: StorageBackend> StorageBackend for Flaky<S> {
+    fn put_blob(&mut self, data: &[u8]) -> Result<String, Error> {
+        self.up()?;
+        self.inner.put_blob(data)
+    }
+
+    fn get_blob(&mut self, url: &str) -> Result<Vec<u8>, Error> {
+        self.up()?;
+        self.inner.get_blob(url)
+    }
+
+    fn post_metadata(&mut self, metadata: &[u8]) -> Result<(), Error> {
+        match self.posts {
+            Some(0) => self.down = true,
+            Some(ref mut posts) => *posts -= 1,
+            None => {}
+        }
+        self.up()?;
+        self.inner.post_metadata(metadata)
+    }
+
+    fn fetch_metadata(&mut self, before: Option<&str>) -> Result<Option<Candidate>, Error> {
+        self.up()?;
+        self.inner.fetch_metadata(before)
+    }
+
+    fn has_blobs(&mut self) -> Result<bool, Error> {
+        self.up()?;
+        self.inner.has_blobs()
+    }
+}
+
+/// One messenger account. Every mount of it sees what earlier ones posted.
+pub struct Harness {
+    server: MockServer,
+}
+
+impl Harness {
+    pub fn new() -> Self {
+        let server = MockServer::start().expect("Could not start mock messenger server");
+        Self { server }
+    }
+
+    pub fn session(&self) -> Session {
+        self.server.session()
+    }
+
+    pub fn mount(&self) -> MessengerFS<Session> {
+        self.mount_with(FsConfig::default())
+            .expect("Could not mount filesystem")
+    }
+
+    pub fn mount_with(&self, config: FsConfig) -> Result<MessengerFS<Session>, Error> {
+        MessengerFS::new(self.session(), config)
+    }
+
+    pub fn mount_flaky(&self) -> MessengerFS<Flaky<Session>> {
+        MessengerFS::new(Flaky::new(self.session()), FsConfig::default())
+            .expect("Could not mount filesystem")
+    }
+
+    /// A session behind the encryption layer, as `main` sets it up.
+    pub fn sealed(&self, passphrase: &str) -> CryptoBackend<Session> {
+        CryptoBackend::new(self.session(), Some(passphrase.to_string()))
+    }
+
+    pub fn mount_sealed(
+        &self,
+        passphrase: &str,
+    ) -> Result<MessengerFS<CryptoBackend<Session>>, Error> {
+        MessengerFS::new_encrypted(self.sealed(passphrase), FsConfig::default())
+    }
+}
+
+pub fn create<S: StorageBackend>(fs: &mut MessengerFS<S>, parent: u64, name: &str) -> u64 {
+    make(fs, parent, name, FileType::RegularFile)
+}
+
+pub fn mkdir<S: StorageBackend>(fs: &mut MessengerFS<S>, parent: u64, name: &str) -> u64 {
+    make(fs, parent, name, FileType::Directory)
+}
+
+fn make<S: StorageBackend>(
+    fs: &mut MessengerFS<S>,
+    parent: u64,
+    name: &str,
+    kind: FileType,
+) -> u64 {
+    fs.fs_create(&ROOT, parent, OsStr::new(name), kind, 0o755, 0)
+        .expect("Could not create entry")
+        .ino
+}
+
+/// Writes `data` at `offset` through a handle of its own.
+pub fn write<S: StorageBackend>(fs: &mut MessengerFS<S>, ino: u64, offset: u64, data: &[u8]) {
+    let fh = fs
+        .fs_open(&ROOT, ino, O_RDWR as u32)
+        .expect("Could not open file");
+    fs.fs_write(ino, fh, offset as i64, data, 0)
+        .expect("Could not write file");
+    fs.fs_release(ino, fh).expect("Could not release file");
+}
+
+pub fn read<S: StorageBackend>(
+    fs: &mut MessengerFS<S>,
+    ino: u64,
+    offset: u64,
+    size: u32,
+) -> Vec<u8> {
+    let fh = fs
+        .fs_open(&ROOT, ino, O_RDWR as u32)
+        .expect("Could not open file");
+    let data = fs
+        .fs_read(ino, fh, offset as i64, size)
+        .expect("Could not read file");
+    fs.fs_release(ino, fh).expect("Could not release file");
+    data
+}
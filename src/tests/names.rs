@@ -0,0 +1,93 @@
+use std::ffi::OsStr;
+use std::os::unix::ffi::OsStrExt;
+
+use fuse::FileType;
+use libc::EINVAL;
+
+use common::constants::METADATA_PADDING_MIN;
+use config::FsConfig;
+use crypto::CryptoBackend;
+use messenger::session::Session;
+use messengerfs::MessengerFS;
+use tests::{create, errno, read, write, Harness, ROOT};
+
+/// Not UTF-8, as a name from another locale can be.
+fn latin1() -> &'static OsStr {
+    OsStr::from_bytes(b"caf\xe9")
+}
+
+#[test]
+fn refuses_names_that_are_not_utf8() {
+    let mut fs = Harness::new().mount();
+    let file = create(&mut fs, 1, "cafe");
+    let cafe = OsStr::new("cafe");
+
+    let created = fs.fs_create(&ROOT, 1, latin1(), FileType::RegularFile, 0o644, 0);
+    assert_eq!(errno(created), EINVAL);
+    assert_eq!(errno(fs.fs_lookup(&ROOT, 1, latin1())), EINVAL);
+    assert_eq!(errno(fs.fs_link(&ROOT, file, 1, latin1())), EINVAL);
+    assert_eq!(errno(fs.fs_rename(&ROOT, 1, cafe, 1, latin1())), EINVAL);
+    assert_eq!(errno(fs.fs_rename(&ROOT, 1, latin1(), 1, cafe)), EINVAL);
+    assert_eq!(errno(fs.fs_delete(&ROOT, 1, latin1(), false)), EINVAL);
+    assert_eq!(fs.fs.lookup(1, "cafe"), Some(file));
+}
+
+fn mount_encrypted(harness: &Harness) -> MessengerFS<CryptoBackend<Session>> {
+    let config = FsConfig {
+        encrypt_names: true,
+        ..FsConfig::default()
+    };
+    MessengerFS::new_encrypted(harness.sealed("secret"), config).unwrap()
+}
+
+#[test]
+fn restores_encrypted_names() {
+    let harness = Harness::new();
+    let mut fs = mount_encrypted(&harness);
+    let file = create(&mut fs, 1, "diary");
+    write(&mut fs, file, 0, b"dear diary");
+    fs.fs_flush().unwrap();
+
+    let mut fs = mount_encrypted(&harness);
+    assert_eq!(
+        fs.fs_lookup(&ROOT, 1, OsStr::new("diary")).unwrap().ino,
+        file
+    );
+    let todo = create(&mut fs, 1, "todo");
+    fs.fs_flush().unwrap();
+
+    let mut fs = mount_encrypted(&harness);
+    assert_eq!(
+        fs.fs_lookup(&ROOT, 1, OsStr::new("todo")).unwrap().ino,
+        todo
+    );
+    assert_eq!(read(&mut fs, file, 0, 100), b"dear diary");
+}
+
+#[test]
+fn hides_names_and_pads_the_snapshot() {
+    let harness = Harness::new();
+    let mut fs = mount_encrypted(&harness);
+    let file = create(&mut fs, 1, "diary");
+    assert!(fs.fs.lookup(1, "diary").is_none());
+    let size = fs.serialize().unwrap().len() as u64;
+    assert!(size.is_power_of_two() && size >= METADATA_PADDING_MIN);
+    fs.fs_flush().unwrap();
+
+    // Turning the setting off stores them in the clear again
+    let mut fs = harness.mount_sealed("secret").unwrap();
+    assert_eq!(fs.fs.lookup(1, "diary"), Some(file));
+    fs.fs_flush().unwrap();
+    let fs = harness.mount_sealed("secret").unwrap();
+    assert_eq!(fs.fs.lookup(1, "diary"), Some(file));
+}
+
+#[test]
+fn encrypted_names_need_a_passphrase() {
+    let config = FsConfig {
+        encrypt_names: true,
+        ..FsConfig::default()
+    };
+    let backend = CryptoBackend::new(Harness::new().session(), None);
+    assert!(MessengerFS::new_encrypted(backend, config).is_err());
+}
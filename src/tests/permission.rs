@@ -0,0 +1,174 @@
+use std::ffi::OsStr;
+
+use failure::Error;
+use fuse::{FileAttr, FileType};
+use libc::{EACCES, EEXIST, EISDIR, EPERM, O_RDONLY, O_RDWR, O_TRUNC, O_WRONLY};
+use time::{self, Timespec};
+
+use common::permission::Caller;
+use messenger::session::Session;
+use messengerfs::MessengerFS;
+use tests::{create, errno, mkdir, Harness, ROOT, USER};
+
+fn create_as(
+    fs: &mut MessengerFS<Session>,
+    caller: &Caller,
+    parent: u64,
+    name: &str,
+    kind: FileType,
+    mode: u32,
+) -> Result<FileAttr, Error> {
+    fs.fs_create(caller, parent, OsStr::new(name), kind, mode, 0)
+}
+
+#[test]
+fn lookup_needs_search_access() {
+    let mut fs = Harness::new().mount();
+    let private = create_as(&mut fs, &ROOT, 1, "private", FileType::Directory, 0o700)
+        .unwrap()
+        .ino;
+    create(&mut fs, private, "secret");
+
+    let secret = OsStr::new("secret");
+    assert_eq!(errno(fs.fs_lookup(&USER, private, secret)), EACCES);
+    assert!(fs.fs_lookup(&ROOT, private, secret).is_ok());
+}
+
+#[test]
+fn create_checks_access_before_existence() {
+    let mut fs = Harness::new().mount();
+    let dir = mkdir(&mut fs, 1, "dir");
+    create(&mut fs, dir, "taken");
+
+    let file = FileType::RegularFile;
+    assert_eq!(
+        errno(create_as(&mut fs, &USER, dir, "taken", file, 0o644)),
+        EACCES
+    );
+    assert_eq!(
+        errno(create_as(&mut fs, &USER, dir, "free", file, 0o644)),
+        EACCES
+    );
+    assert_eq!(
+        errno(create_as(&mut fs, &ROOT, dir, "taken", file, 0o644)),
+        EEXIST
+    );
+}
+
+#[test]
+fn xattrs_need_read_and_write_access() {
+    let mut fs = Harness::new().mount();
+    let file = FileType::RegularFile;
+    let shared = create_as(&mut fs, &ROOT, 1, "shared", file, 0o644)
+        .unwrap()
+        .ino;
+    let private = create_as(&mut fs, &ROOT, 1, "private", file, 0o600)
+        .unwrap()
+        .ino;
+    let tag = OsStr::new("user.tag");
+    for &ino in &[shared, private] {
+        fs.fs_setxattr(&ROOT, ino, tag, b"draft", 0).unwrap();
+    }
+
+    assert_eq!(fs.fs_getxattr(&USER, shared, tag).unwrap(), b"draft");
+    assert_eq!(fs.fs_listxattr(&USER, shared).unwrap(), b"user.tag\0");
+    assert_eq!(
+        errno(fs.fs_setxattr(&USER, shared, tag, b"final", 0)),
+        EACCES
+    );
+    assert_eq!(errno(fs.fs_removexattr(&USER, shared, tag)), EACCES);
+
+    assert_eq!(errno(fs.fs_getxattr(&USER, private, tag)), EACCES);
+    assert_eq!(errno(fs.fs_listxattr(&USER, private)), EACCES);
+    assert_eq!(fs.fs_getxattr(&ROOT, private, tag).unwrap(), b"draft");
+}
+
+#[test]
+fn open_needs_access_for_the_mode() {
+    let mut fs = Harness::new().mount();
+    let file = create_as(&mut fs, &ROOT, 1, "notes", FileType::RegularFile, 0o644)
+        .unwrap()
+        .ino;
+
+    assert!(fs.fs_open(&USER, file, O_RDONLY as u32).is_ok());
+    assert_eq!(errno(fs.fs_open(&USER, file, O_WRONLY as u32)), EACCES);
+    assert_eq!(errno(fs.fs_open(&USER, file, O_RDWR as u32)), EACCES);
+    let truncate = (O_RDONLY | O_TRUNC) as u32;
+    assert_eq!(errno(fs.fs_open(&USER, file, truncate)), EACCES);
+    assert!(fs.fs_open(&ROOT, file, O_RDWR as u32).is_ok());
+}
+
+#[test]
+fn sticky_directories_keep_others_entries() {
+    let mut fs = Harness::new().mount();
+    let dir = FileType::Directory;
+    let tmp = create_as(&mut fs, &ROOT, 1, "tmp", dir, 0o1777)
+        .unwrap()
+        .ino;
+    let file = FileType::RegularFile;
+    create_as(&mut fs, &ROOT, tmp, "theirs", file, 0o666).unwrap();
+    create_as(&mut fs, &ROOT, tmp, "shared", dir, 0o777).unwrap();
+    create_as(&mut fs, &USER, tmp, "mine", file, 0o644).unwrap();
+
+    assert_eq!(
+        errno(fs.fs_delete(&USER, tmp, OsStr::new("theirs"), false)),
+        EPERM
+    );
+    assert_eq!(
+        errno(fs.fs_delete(&USER, tmp, OsStr::new("shared"), true)),
+        EPERM
+    );
+    fs.fs_delete(&USER, tmp, OsStr::new("mine"), false).unwrap();
+    fs.fs_delete(&ROOT, tmp, OsStr::new("theirs"), false)
+        .unwrap();
+}
+
+#[test]
+fn setattr_needs_ownership() {
+    let mut fs = Harness::new().mount();
+    let file = create_as(&mut fs, &ROOT, 1, "shared", FileType::RegularFile, 0o666)
+        .unwrap()
+        .ino;
+    let now = Some(time::now().to_timespec());
+    let epoch = Some(Timespec::new(0, 0));
+
+    assert!(fs
+        .check_setattr(&USER, file, None, None, None, &[now, now])
+        .is_ok());
+    assert_eq!(
+        errno(fs.check_setattr(&USER, file, None, None, None, &[epoch, None])),
+        EPERM
+    );
+    assert_eq!(
+        errno(fs.check_setattr(&USER, file, Some(0o600), None, None, &[])),
+        EPERM
+    );
+    assert_eq!(
+        errno(fs.check_setattr(&USER, file, None, Some(USER.uid), None, &[])),
+        EPERM
+    );
+    assert!(fs
+        .check_setattr(&ROOT, file, None, None, None, &[epoch, epoch])
+        .is_ok());
+
+    let mine = create_as(&mut fs, &USER, 1, "mine", FileType::RegularFile, 0o644)
+        .unwrap()
+        .ino;
+    assert!(fs
+        .check_setattr(&USER, mine, Some(0o600), None, None, &[epoch, epoch])
+        .is_ok());
+    assert!(fs
+        .check_setattr(&USER, mine, None, None, Some(USER.gid), &[])
+        .is_ok());
+    assert_eq!(
+        errno(fs.check_setattr(&USER, mine, None, None, Some(0), &[])),
+        EPERM
+    );
+}
+
+#[test]
+fn truncating_a_directory_fails() {
+    let mut fs = Harness::new().mount();
+    let dir = mkdir(&mut fs, 1, "dir");
+    assert_eq!(errno(fs.fs_truncate(dir, 0)), EISDIR);
+}
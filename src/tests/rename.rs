@@ -0,0 +1,90 @@
+use std::ffi::OsStr;
+
+use failure::Error;
+use libc::{EINVAL, EISDIR, ENOTDIR, ENOTEMPTY};
+
+use messengerfs::MessengerFS;
+use storage::StorageBackend;
+use tests::{create, errno, mkdir, read, write, Harness, ROOT};
+
+fn rename<S: StorageBackend>(
+    fs: &mut MessengerFS<S>,
+    parent: u64,
+    name: &str,
+    newparent: u64,
+    newname: &str,
+) -> Result<(), Error> {
+    fs.fs_rename(
+        &ROOT,
+        parent,
+        OsStr::new(name),
+        newparent,
+        OsStr::new(newname),
+    )
+}
+
+fn nlink<S>(fs: &MessengerFS<S>, ino: u64) -> u32 {
+    fs.fs.get(ino).unwrap().entry.attr.nlink
+}
+
+#[test]
+fn moves_across_directories() {
+    let harness = Harness::new();
+    let mut fs = harness.mount();
+    let from = mkdir(&mut fs, 1, "from");
+    let to = mkdir(&mut fs, 1, "to");
+    let dir = mkdir(&mut fs, from, "dir");
+    let file = create(&mut fs, from, "file");
+    rename(&mut fs, from, "dir", to, "dir").unwrap();
+    rename(&mut fs, from, "file", to, "renamed").unwrap();
+    assert_eq!(nlink(&fs, from), 2);
+    assert_eq!(nlink(&fs, to), 3);
+    fs.fs_flush().unwrap();
+
+    let fs = harness.mount();
+    assert!(fs.fs.lookup(from, "dir").is_none());
+    assert!(fs.fs.lookup(from, "file").is_none());
+    assert_eq!(fs.fs.lookup(to, "dir"), Some(dir));
+    assert_eq!(fs.fs.lookup(to, "renamed"), Some(file));
+    assert_eq!(fs.fs.get(dir).unwrap().parent, Some(to));
+}
+
+#[test]
+fn replaces_the_target() {
+    let mut fs = Harness::new().mount();
+    let kept = create(&mut fs, 1, "kept");
+    write(&mut fs, kept, 0, b"new");
+    let replaced = create(&mut fs, 1, "replaced");
+    write(&mut fs, replaced, 0, b"old");
+    rename(&mut fs, 1, "kept", 1, "replaced").unwrap();
+
+    assert!(fs.fs.lookup(1, "kept").is_none());
+    assert_eq!(fs.fs.lookup(1, "replaced"), Some(kept));
+    assert!(fs.fs.get(replaced).is_none());
+    assert_eq!(read(&mut fs, kept, 0, 100), b"new");
+}
+
+#[test]
+fn refuses_to_mix_files_and_directories() {
+    let mut fs = Harness::new().mount();
+    let dir = mkdir(&mut fs, 1, "dir");
+    create(&mut fs, 1, "file");
+    create(&mut fs, dir, "entry");
+    mkdir(&mut fs, 1, "empty");
+
+    assert_eq!(errno(rename(&mut fs, 1, "file", 1, "dir")), EISDIR);
+    assert_eq!(errno(rename(&mut fs, 1, "empty", 1, "file")), ENOTDIR);
+    assert_eq!(errno(rename(&mut fs, 1, "empty", 1, "dir")), ENOTEMPTY);
+    rename(&mut fs, 1, "dir", 1, "empty").unwrap();
+}
+
+#[test]
+fn refuses_to_move_a_directory_into_itself() {
+    let mut fs = Harness::new().mount();
+    let dir = mkdir(&mut fs, 1, "dir");
+    let sub = mkdir(&mut fs, dir, "sub");
+
+    assert_eq!(errno(rename(&mut fs, 1, "dir", sub, "dir")), EINVAL);
+    assert_eq!(errno(rename(&mut fs, 1, "dir", dir, "dir")), EINVAL);
+    assert_eq!(fs.fs.lookup(1, "dir"), Some(dir));
+}
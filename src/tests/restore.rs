@@ -0,0 +1,137 @@
+use failure::{err_msg, Error};
+
+use messengerfs::MessengerFS;
+use storage::{Candidate, StorageBackend};
+use superblock::{self, Kind, MissingSuperblock};
+use tests::{create, read, write, Harness};
+
+/// Fails to list blobs, as if the connection dropped mid-restore.
+struct Unreachable<S> {
+    inner: S,
+}
+
+impl<S: StorageBackend> StorageBackend for Unreachable<S> {
+    fn put_blob(&mut self, data: &[u8]) -> Result<String, Error> {
+        self.inner.put_blob(data)
+    }
+
+    fn get_blob(&mut self, url: &str) -> Result<Vec<u8>, Error> {
+        self.inner.get_blob(url)
+    }
+
+    fn post_metadata(&mut self, metadata: &[u8]) -> Result<(), Error> {
+        self.inner.post_metadata(metadata)
+    }
+
+    fn fetch_metadata(&mut self, before: Option<&str>) -> Result<Option<Candidate>, Error> {
+        self.inner.fetch_metadata(before)
+    }
+
+    fn has_blobs(&mut self) -> Result<bool, Error> {
+        Err(err_msg("Connection reset"))
+    }
+}
+
+#[test]
+fn skips_a_snapshot_that_does_not_decrypt() {
+    let harness = Harness::new();
+    let mut fs = harness.mount_sealed("secret").unwrap();
+    let file = create(&mut fs, 1, "diary");
+    write(&mut fs, file, 0, b"dear diary");
+    fs.fs_flush().unwrap();
+
+    let mut session = harness.session();
+    let mut damaged = session
+        .fetch_metadata(None)
+        .unwrap()
+        .unwrap()
+        .metadata
+        .unwrap();
+    let last = damaged.len() - 1;
+    damaged[last] ^= 0xff;
+    session.post_metadata(&damaged).unwrap();
+
+    let mut fs = harness.mount_sealed("secret").unwrap();
+    assert_eq!(read(&mut fs, file, 0, 100), b"dear diary");
+}
+
+#[test]
+fn keeps_the_volume_on_a_wrong_passphrase() {
+    let harness = Harness::new();
+    let mut fs = harness.mount_sealed("secret").unwrap();
+    let file = create(&mut fs, 1, "diary");
+    write(&mut fs, file, 0, b"dear diary");
+    fs.fs_flush().unwrap();
+
+    assert!(harness.mount_sealed("guess").is_err());
+    let mut fs = harness.mount_sealed("secret").unwrap();
+    assert_eq!(read(&mut fs, file, 0, 100), b"dear diary");
+}
+
+#[test]
+fn fails_when_the_backend_does() {
+    let harness = Harness::new();
+    let backend = Unreachable {
+        inner: harness.session(),
+    };
+    assert!(MessengerFS::new(backend, Default::default()).is_err());
+}
+
+#[test]
+fn refuses_to_start_over_on_blobs_without_a_superblock() {
+    let harness = Harness::new();
+    let mut session = harness.session();
+    session.put_blob(b"orphaned block").unwrap();
+    session.post_metadata(b"just chatting").unwrap();
+
+    let err = harness
+        .mount_with(Default::default())
+        .err()
+        .expect("Mounted over orphaned blocks");
+    assert!(err.downcast_ref::<MissingSuperblock>().is_some());
+}
+
+/// `journal` with its first change under an inode that doesn't exist.
+fn orphaned(journal: &[u8]) -> Vec<u8> {
+    use serde_cbor::Value;
+
+    let (header, payload) = superblock::decode(journal).unwrap();
+    assert!(header.kind == Kind::Journal);
+    let mut entry: Value = serde_cbor::from_slice(&zstd::decode_all(payload).unwrap()).unwrap();
+    let text = |key: &str| Value::Text(key.to_owned());
+    if let Value::Map(ref mut entry) = entry {
+        if let Some(Value::Array(ref mut changes)) = entry.get_mut(&text("changes")) {
+            if let Value::Map(ref mut change) = changes[0] {
+                if let Some(Value::Map(ref mut create)) = change.get_mut(&text("Create")) {
+                    create.insert(text("parent"), Value::Integer(999));
+                }
+            }
+        }
+    }
+    let payload = zstd::encode_all(&serde_cbor::to_vec(&entry).unwrap()[..], 0).unwrap();
+    superblock::encode(Kind::Journal, header.seq, &payload, false)
+}
+
+#[test]
+fn restores_the_checkpoint_when_the_journal_does_not_apply() {
+    let harness = Harness::new();
+    let mut fs = harness.mount();
+    let kept = create(&mut fs, 1, "kept");
+    write(&mut fs, kept, 0, b"checkpointed");
+    fs.fs_flush().unwrap();
+    create(&mut fs, 1, "lost");
+    fs.fs_flush().unwrap();
+
+    let mut session = harness.session();
+    let journal = session
+        .fetch_metadata(None)
+        .unwrap()
+        .unwrap()
+        .metadata
+        .unwrap();
+    session.post_metadata(&orphaned(&journal)).unwrap();
+
+    let mut fs = harness.mount();
+    assert_eq!(read(&mut fs, kept, 0, 100), b"checkpointed");
+    assert!(fs.fs.lookup(1, "lost").is_none());
+}
@@ -0,0 +1,145 @@
+//! `baseline.json` is metadata as the first release posted it.
+
+use fuse::FileType;
+
+use entry::FileSystemEntry;
+use messengerfs::MessengerFS;
+use schema::{self, SCHEMA_VERSION};
+use storage::StorageBackend;
+use superblock::{self, Kind};
+use tests::{create, Harness};
+
+const BASELINE: &[u8] = include_bytes!("fixtures/baseline.json");
+
+type Fs = MessengerFS<()>;
+
+/// The decompressed payload of a superblock.
+fn payload(superblock: &[u8]) -> Vec<u8> {
+    let (_, payload) = superblock::decode(superblock).unwrap();
+    zstd::decode_all(payload).unwrap()
+}
+
+/// The metadata posted last.
+fn posted(harness: &Harness) -> Vec<u8> {
+    let candidate = harness.session().fetch_metadata(None).unwrap().unwrap();
+    candidate.metadata.unwrap()
+}
+
+/// A journal entry as this version posts it.
+fn journal(harness: &Harness) -> Vec<u8> {
+    let mut fs = harness.mount();
+    fs.fs_flush().unwrap();
+    create(&mut fs, 1, "todo");
+    fs.fs_flush().unwrap();
+    let posted = posted(harness);
+    assert!(superblock::decode(&posted).unwrap().0.kind == Kind::Journal);
+    payload(&posted)
+}
+
+fn entry<'a, S>(fs: &'a MessengerFS<S>, path: &str) -> &'a FileSystemEntry {
+    let ino = path
+        .split('/')
+        .filter(|name| !name.is_empty())
+        .fold(1, |parent, name| {
+            fs.fs
+                .lookup(parent, name)
+                .unwrap_or_else(|| panic!("No entry {}", path))
+        });
+    &fs.fs.get(ino).unwrap().entry
+}
+
+/// Checks the kind, link count and size of each path.
+fn assert_tree<S>(fs: &MessengerFS<S>, expected: &[(&str, FileType, u32, u64)]) {
+    for &(path, kind, nlink, size) in expected {
+        let attr = &entry(fs, path).attr;
+        assert!(attr.kind.unmarshal() == kind, "Wrong kind for {}", path);
+        assert_eq!(attr.nlink, nlink, "Wrong link count for {}", path);
+        assert_eq!(attr.size, size, "Wrong size for {}", path);
+    }
+}
+
+/// The files the baseline was written with.
+fn assert_files<S>(fs: &MessengerFS<S>) {
+    assert_tree(
+        fs,
+        &[
+            ("/", FileType::Directory, 3, 0),
+            ("/docs", FileType::Directory, 3, 0),
+            ("/docs/notes.txt", FileType::RegularFile, 1, 12),
+            ("/docs/old", FileType::Directory, 2, 0),
+            ("/readme", FileType::RegularFile, 1, 0),
+        ],
+    );
+}
+
+#[test]
+fn upgrades_the_baseline_snapshot() {
+    let upgraded: serde_json::Value = schema::decode_legacy(BASELINE).unwrap();
+    assert_eq!(upgraded["schema"], SCHEMA_VERSION);
+    assert!(upgraded.get("inodes").is_none());
+
+    let fs: Fs = schema::decode_legacy(BASELINE).unwrap();
+    assert_files(&fs);
+}
+
+#[test]
+fn restores_the_baseline_snapshot() {
+    let harness = Harness::new();
+    harness.session().post_metadata(BASELINE).unwrap();
+
+    let mut fs = harness.mount();
+    assert_files(&fs);
+    fs.fs_flush().unwrap();
+    assert!(superblock::is_superblock(&posted(&harness)));
+
+    let fs = harness.mount();
+    assert_files(&fs);
+}
+
+#[test]
+fn reads_a_superblock_in_the_current_schema() {
+    let harness = Harness::new();
+    let mut fs = harness.mount();
+    create(&mut fs, 1, "todo");
+
+    let (restored, upgraded): (Fs, _) =
+        schema::decode_snapshot(&payload(&fs.serialize().unwrap())).unwrap();
+    assert!(!upgraded);
+    assert_tree(&restored, &[("/todo", FileType::RegularFile, 1, 0)]);
+}
+
+/// `payload` with its schema replaced, or removed.
+fn with_schema(payload: &[u8], schema: Option<u32>) -> Vec<u8> {
+    let mut value: serde_cbor::Value = serde_cbor::from_slice(payload).unwrap();
+    if let serde_cbor::Value::Map(ref mut fields) = value {
+        let key = serde_cbor::Value::Text("schema".to_owned());
+        match schema {
+            Some(schema) => fields.insert(key, serde_cbor::Value::Integer(schema.into())),
+            None => fields.remove(&key),
+        };
+    }
+    serde_cbor::to_vec(&value).unwrap()
+}
+
+#[test]
+fn rejects_a_newer_schema() {
+    let harness = Harness::new();
+    let snapshot = payload(&harness.mount().serialize().unwrap());
+    let snapshot = with_schema(&snapshot, Some(SCHEMA_VERSION + 1));
+    assert!(schema::decode_snapshot::<Fs>(&snapshot).is_err());
+    let journal = with_schema(&journal(&harness), Some(SCHEMA_VERSION + 1));
+    assert!(schema::decode_journal::<serde_json::Value>(&journal).is_err());
+}
+
+#[test]
+fn rejects_a_superblock_without_a_schema() {
+    let harness = Harness::new();
+    let snapshot = payload(&harness.mount().serialize().unwrap());
+    assert!(schema::decode_snapshot::<Fs>(&with_schema(&snapshot, None)).is_err());
+}
+
+#[test]
+fn rejects_a_journal_entry_that_needs_the_whole_tree() {
+    let journal = with_schema(&journal(&Harness::new()), Some(SCHEMA_VERSION - 1));
+    assert!(schema::decode_journal::<serde_json::Value>(&journal).is_err());
+}
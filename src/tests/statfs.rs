@@ -0,0 +1,37 @@
+use common::constants::{MEGABYTES, STATFS_BLOCK_SIZE};
+use config::FsConfig;
+use tests::{create, write, Harness};
+
+#[test]
+fn reports_usage_against_the_quota() {
+    let config = FsConfig {
+        quota: MEGABYTES,
+        ..FsConfig::default()
+    };
+    let mut fs = Harness::new().mount_with(config).unwrap();
+    let total = MEGABYTES / STATFS_BLOCK_SIZE;
+    let stats = fs.fs_statfs();
+    assert_eq!((stats.blocks, stats.free), (total, total));
+    assert_eq!((stats.files, stats.ffree), (1 + total, total));
+
+    let file = create(&mut fs, 1, "notes");
+    write(&mut fs, file, 0, &[7; 10_000]);
+    let stats = fs.fs_statfs();
+    assert_eq!(stats.blocks, total);
+    assert_eq!(stats.free, total - 3);
+    assert_eq!((stats.files, stats.ffree), (2 + total - 3, total - 3));
+}
+
+#[test]
+fn reports_nothing_free_over_the_quota() {
+    let config = FsConfig {
+        quota: STATFS_BLOCK_SIZE,
+        ..FsConfig::default()
+    };
+    let mut fs = Harness::new().mount_with(config).unwrap();
+    let file = create(&mut fs, 1, "notes");
+    write(&mut fs, file, 0, &[7; 10_000]);
+    let stats = fs.fs_statfs();
+    assert_eq!((stats.blocks, stats.free, stats.ffree), (1, 0, 0));
+    assert_eq!(stats.files, 2);
+}
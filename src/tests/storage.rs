@@ -0,0 +1,165 @@
+use libc::O_RDONLY;
+
+use common::constants::MEGABYTES;
+use storage::StorageBackend;
+use superblock;
+use tests::{create, mkdir, read, write, Harness, ROOT};
+
+#[test]
+fn restores_what_was_flushed() {
+    let harness = Harness::new();
+    let mut fs = harness.mount();
+    let dir = mkdir(&mut fs, 1, "docs");
+    let file = create(&mut fs, dir, "notes.txt");
+    write(&mut fs, file, 0, b"hello, world");
+    fs.fs_flush().unwrap();
+
+    let mut fs = harness.mount();
+    assert_eq!(fs.fs.lookup(1, "docs"), Some(dir));
+    assert_eq!(fs.fs.lookup(dir, "notes.txt"), Some(file));
+    assert_eq!(read(&mut fs, file, 0, 100), b"hello, world");
+    assert_eq!(read(&mut fs, file, 7, 5), b"world");
+}
+
+#[test]
+fn loses_what_was_not_flushed() {
+    let harness = Harness::new();
+    let mut fs = harness.mount();
+    let kept = create(&mut fs, 1, "kept");
+    write(&mut fs, kept, 0, b"first");
+    fs.fs_flush().unwrap();
+    create(&mut fs, 1, "lost");
+    write(&mut fs, kept, 0, b"second");
+
+    let mut fs = harness.mount();
+    assert!(fs.fs.lookup(1, "lost").is_none());
+    assert_eq!(read(&mut fs, kept, 0, 100), b"first");
+}
+
+#[test]
+fn keeps_writing_after_a_restore() {
+    let harness = Harness::new();
+    let mut fs = harness.mount();
+    let file = create(&mut fs, 1, "log");
+    write(&mut fs, file, 0, b"one ");
+    fs.fs_flush().unwrap();
+
+    let mut fs = harness.mount();
+    write(&mut fs, file, 4, b"two ");
+    let other = create(&mut fs, 1, "other");
+    fs.fs_flush().unwrap();
+
+    let mut fs = harness.mount();
+    assert_eq!(read(&mut fs, file, 0, 100), b"one two ");
+    assert_eq!(fs.fs.lookup(1, "other"), Some(other));
+}
+
+#[test]
+fn fails_to_read_from_a_missing_block() {
+    let harness = Harness::new();
+    let mut fs = harness.mount();
+    let file = create(&mut fs, 1, "notes.txt");
+    write(&mut fs, file, 0, b"hello, world");
+    fs.fs_flush().unwrap();
+
+    let mut fs = harness.mount();
+    fs.blocks.arena.get_mut().clear();
+    let fh = fs.fs_open(&ROOT, file, O_RDONLY as u32).unwrap();
+    assert!(fs.fs_read(file, fh, 0, 100).is_err());
+    fs.fs_release(file, fh).unwrap();
+}
+
+#[test]
+fn stores_nothing_for_a_hole() {
+    let harness = Harness::new();
+    let mut fs = harness.mount();
+    let file = create(&mut fs, 1, "sparse");
+    let end = 64 * MEGABYTES;
+    write(&mut fs, file, 0, b"head");
+    write(&mut fs, file, end, b"tail");
+    write(&mut fs, file, MEGABYTES, b"middle");
+    assert!(fs.blocks.used() < 100);
+    fs.fs_flush().unwrap();
+
+    let mut fs = harness.mount();
+    assert_eq!(read(&mut fs, file, 0, 6), b"head\0\0");
+    assert_eq!(read(&mut fs, file, MEGABYTES - 2, 10), b"\0\0middle\0\0");
+    assert_eq!(read(&mut fs, file, end - 2, 10), b"\0\0tail");
+}
+
+/// The metadata posted last.
+fn posted(harness: &Harness) -> Vec<u8> {
+    let candidate = harness.session().fetch_metadata(None).unwrap().unwrap();
+    candidate.metadata.unwrap()
+}
+
+#[test]
+fn posts_nothing_when_nothing_changed() {
+    let harness = Harness::new();
+    let mut fs = harness.mount();
+    let file = create(&mut fs, 1, "notes.txt");
+    write(&mut fs, file, 0, b"hello, world");
+    fs.fs_flush().unwrap();
+    let posted = posted(&harness);
+
+    read(&mut fs, file, 0, 100);
+    fs.fs_flush().unwrap();
+    assert_eq!(self::posted(&harness), posted);
+    write(&mut fs, file, 0, b"H");
+    fs.fs_flush().unwrap();
+    assert!(self::posted(&harness) != posted);
+}
+
+#[test]
+fn journals_only_the_extents_that_changed() {
+    let harness = Harness::new();
+    let mut fs = harness.mount();
+    let file = create(&mut fs, 1, "sparse");
+    // Every other byte, so that each one is an extent of its own
+    for offset in 0..2000 {
+        write(&mut fs, file, offset * 2 + 1, b"x");
+    }
+    fs.fs_flush().unwrap();
+    let checkpoint = posted(&harness).len();
+
+    write(&mut fs, file, 4000, b"end");
+    fs.fs_flush().unwrap();
+    let journal = posted(&harness).len();
+    assert!(journal * 4 < checkpoint);
+
+    fs.fs_truncate(file, 500).unwrap();
+    write(&mut fs, file, 0, b"start");
+    fs.fs_flush().unwrap();
+
+    let mut fs = harness.mount();
+    assert_eq!(read(&mut fs, file, 0, 8), b"startx\0x");
+    assert_eq!(read(&mut fs, file, 496, 100), b"\0x\0x");
+}
+
+#[test]
+fn posts_a_large_snapshot_behind_a_pointer() {
+    let harness = Harness::new();
+    let mut fs = harness.mount();
+    // Names that don't compress, so the snapshot is too long for a message
+    let files = (0..1000u64)
+        .map(|i| format!("{:016x}", i.wrapping_mul(0x9e37_79b9_7f4a_7c15)))
+        .map(|name| (create(&mut fs, 1, &name), name))
+        .collect::<Vec<_>>();
+    write(&mut fs, files[0].0, 0, b"hello, world");
+    fs.fs_flush().unwrap();
+    let pointer = superblock::decode_pointer(&posted(&harness)).unwrap();
+    assert_eq!(pointer.parts.len(), 1);
+
+    let mut fs = harness.mount();
+    for (file, name) in &files {
+        assert_eq!(fs.fs.lookup(1, name), Some(*file));
+    }
+    // Changes after it are journaled as usual
+    write(&mut fs, files[0].0, 0, b"H");
+    fs.fs_flush().unwrap();
+    assert!(!superblock::is_pointer(&posted(&harness)));
+
+    let mut fs = harness.mount();
+    assert_eq!(read(&mut fs, files[0].0, 0, 100), b"Hello, world");
+    assert_eq!(fs.fs.lookup(1, &files[999].1), Some(files[999].0));
+}
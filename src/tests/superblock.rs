@@ -0,0 +1,59 @@
+use storage::StorageBackend;
+use superblock::{self, Kind};
+use tests::{create, Harness};
+
+fn posted(harness: &Harness) -> Vec<u8> {
+    let candidate = harness.session().fetch_metadata(None).unwrap().unwrap();
+    candidate.metadata.unwrap()
+}
+
+#[test]
+fn rejects_a_tampered_payload() {
+    let encoded = superblock::encode(Kind::Checkpoint, 7, b"payload", false);
+    let (header, payload) = superblock::decode(&encoded).unwrap();
+    assert_eq!((header.seq, payload), (7, &b"payload"[..]));
+
+    let mut tampered = encoded.clone();
+    let last = tampered.len() - 1;
+    tampered[last] ^= 1;
+    assert!(superblock::decode(&tampered).is_err());
+    let mut tampered = encoded.clone();
+    tampered[30] ^= 1;
+    assert!(superblock::decode(&tampered).is_err());
+    assert!(superblock::decode(&encoded[..encoded.len() - 1]).is_err());
+}
+
+#[test]
+fn ignores_padding() {
+    let encoded = superblock::encode(Kind::Journal, 1, b"payload", true);
+    let mut padded = encoded.clone();
+    let last = padded.len() - 1;
+    padded[last] = 0xff;
+    let (header, payload) = superblock::decode(&padded).unwrap();
+    assert!(header.kind == Kind::Journal);
+    assert_eq!(payload, b"payload");
+}
+
+#[test]
+fn skips_a_tampered_superblock() {
+    let harness = Harness::new();
+    let mut fs = harness.mount();
+    let first = create(&mut fs, 1, "first");
+    fs.fs_flush().unwrap();
+    let second = create(&mut fs, 1, "second");
+    fs.fs_flush().unwrap();
+    let mut tampered = posted(&harness);
+    let last = tampered.len() - 1;
+    tampered[last] ^= 1;
+    harness.session().post_metadata(&tampered).unwrap();
+
+    let mut fs = harness.mount();
+    assert_eq!(fs.fs.lookup(1, "first"), Some(first));
+    assert_eq!(fs.fs.lookup(1, "second"), Some(second));
+    let third = create(&mut fs, 1, "third");
+    fs.fs_flush().unwrap();
+    assert!(superblock::decode(&posted(&harness)).is_ok());
+
+    let fs = harness.mount();
+    assert_eq!(fs.fs.lookup(1, "third"), Some(third));
+}
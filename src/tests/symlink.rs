@@ -0,0 +1,50 @@
+use std::ffi::OsStr;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+
+use fuse::FileType;
+use libc::{EEXIST, EINVAL};
+
+use tests::{create, errno, Harness, ROOT};
+
+#[test]
+fn reads_back_the_target() {
+    let harness = Harness::new();
+    let mut fs = harness.mount();
+    let attr = fs
+        .fs_symlink(&ROOT, 1, OsStr::new("latest"), Path::new("logs/today.txt"))
+        .unwrap();
+    assert!(attr.kind == FileType::Symlink);
+    assert_eq!(attr.size, 14);
+    assert_eq!(fs.fs_readlink(attr.ino).unwrap(), b"logs/today.txt");
+    fs.fs_flush().unwrap();
+
+    let fs = harness.mount();
+    assert_eq!(fs.fs.lookup(1, "latest"), Some(attr.ino));
+    assert_eq!(fs.fs_readlink(attr.ino).unwrap(), b"logs/today.txt");
+}
+
+#[test]
+fn refuses_to_read_a_file_as_a_link() {
+    let mut fs = Harness::new().mount();
+    let file = create(&mut fs, 1, "file");
+    assert_eq!(errno(fs.fs_readlink(file)), EINVAL);
+}
+
+#[test]
+fn refuses_a_taken_name_or_a_target_that_is_not_utf8() {
+    let mut fs = Harness::new().mount();
+    create(&mut fs, 1, "file");
+    let target = Path::new("elsewhere");
+    assert_eq!(
+        errno(fs.fs_symlink(&ROOT, 1, OsStr::new("file"), target)),
+        EEXIST
+    );
+
+    let latin1 = Path::new(OsStr::from_bytes(b"caf\xe9"));
+    assert_eq!(
+        errno(fs.fs_symlink(&ROOT, 1, OsStr::new("link"), latin1)),
+        EINVAL
+    );
+    assert!(fs.fs.lookup(1, "link").is_none());
+}
@@ -0,0 +1,46 @@
+use messengerfs::MessengerFS;
+use tests::{create, read, write, Harness};
+
+fn size<S>(fs: &MessengerFS<S>, ino: u64) -> u64 {
+    fs.fs.get(ino).unwrap().entry.attr.size
+}
+
+#[test]
+fn shrinks_a_file() {
+    let harness = Harness::new();
+    let mut fs = harness.mount();
+    let file = create(&mut fs, 1, "notes");
+    write(&mut fs, file, 0, b"hello, world");
+    fs.fs_truncate(file, 5).unwrap();
+    assert_eq!(size(&fs, file), 5);
+    assert_eq!(read(&mut fs, file, 0, 100), b"hello");
+    fs.fs_flush().unwrap();
+
+    let mut fs = harness.mount();
+    assert_eq!(size(&fs, file), 5);
+    assert_eq!(read(&mut fs, file, 0, 100), b"hello");
+}
+
+#[test]
+fn grows_a_file_with_zeros() {
+    let harness = Harness::new();
+    let mut fs = harness.mount();
+    let file = create(&mut fs, 1, "notes");
+    write(&mut fs, file, 0, b"hi");
+    fs.fs_truncate(file, 6).unwrap();
+    assert_eq!(read(&mut fs, file, 0, 100), b"hi\0\0\0\0");
+    fs.fs_flush().unwrap();
+
+    let mut fs = harness.mount();
+    assert_eq!(read(&mut fs, file, 0, 100), b"hi\0\0\0\0");
+}
+
+#[test]
+fn shrinking_then_growing_drops_the_old_tail() {
+    let mut fs = Harness::new().mount();
+    let file = create(&mut fs, 1, "notes");
+    write(&mut fs, file, 0, b"secret");
+    fs.fs_truncate(file, 0).unwrap();
+    fs.fs_truncate(file, 6).unwrap();
+    assert_eq!(read(&mut fs, file, 0, 100), b"\0\0\0\0\0\0");
+}